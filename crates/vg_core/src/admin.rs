@@ -0,0 +1,241 @@
+//! Local HTTP API for running the proxy without its console, served on
+//! `admin.ip`:`admin.port` while `admin.active`. Every request needs an
+//! `Authorization: Bearer <admin.token>` header and every answer is JSON.
+//!
+//! - `GET /stats`
+//! - `GET /sessions`, `POST /sessions/{id}/kick`
+//! - `GET /players`, `POST /players/{username}/kick`
+//! - `GET /connections`
+//! - `GET /bans`, `POST /bans`, `DELETE /bans/{ip}`
+//! - `GET /filters/{blacklist|whitelist}`, `POST /filters/{list}`,
+//!   `DELETE /filters/{list}/{ip}`
+//! - `POST /reload`
+//! - `PUT /attack-mode`, `PUT /maintenance`
+//!
+//! Kicks take an optional `{"reason": ".."}`, additions to a list take
+//! `{"ip": ".."}` and the two toggles take `{"active": true}`.
+
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::file::ip_filter_file::IpFilter;
+use crate::file::{IP_BANLIST_DB, IP_BLACKLIST_DB, IP_WHITELIST_DB, VIGILANT_CONFIG, VIGILANT_LANG};
+use crate::guardian::{self, ATTACK_MODE};
+use crate::macros::coloriser;
+use crate::metrics::{BYTES_C2S, BYTES_S2C};
+use crate::session::{self, Session};
+use crate::shutdown::{self, DRAINING};
+use crate::{CONNECTIONS, PLAYERS, SERVER_ALIVE, SESSIONS};
+
+#[derive(Deserialize, Default)]
+struct KickBody {
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct IpBody {
+    ip: IpAddr,
+}
+
+#[derive(Deserialize)]
+struct ToggleBody {
+    active: bool,
+}
+
+type Answer = (StatusCode, Value);
+
+fn ok(value: Value) -> Answer {
+    (StatusCode::OK, value)
+}
+
+fn fail(status: StatusCode, message: &str) -> Answer {
+    (status, json!({ "error": message }))
+}
+
+pub async fn stats() -> Value {
+    json!({
+        "connections": SESSIONS.lock().await.len(),
+        "players": PLAYERS.lock().await.len(),
+        "bytes_c2s": BYTES_C2S.load(Ordering::Relaxed),
+        "bytes_s2c": BYTES_S2C.load(Ordering::Relaxed),
+        "backend_up": SERVER_ALIVE.load(Ordering::Relaxed),
+        "attack_mode": ATTACK_MODE.load(Ordering::Relaxed),
+        "maintenance": DRAINING.load(Ordering::Relaxed),
+    })
+}
+
+fn filter_db(list: &str) -> Option<&'static Mutex<IpFilter>> {
+    match list {
+        "blacklist" => Some(&IP_BLACKLIST_DB),
+        "whitelist" => Some(&IP_WHITELIST_DB),
+        _ => None,
+    }
+}
+
+async fn body<T: DeserializeOwned>(request: Request<Body>) -> Result<T, Answer> {
+    let bytes = hyper::body::to_bytes(request.into_body()).await.map_err(|err| fail(StatusCode::BAD_REQUEST, &err.to_string()))?;
+    serde_json::from_slice(&bytes).map_err(|err| fail(StatusCode::BAD_REQUEST, &err.to_string()))
+}
+
+/// Like [`body`], but an empty body is the default
+async fn optional_body<T: DeserializeOwned + Default>(request: Request<Body>) -> Result<T, Answer> {
+    let bytes = hyper::body::to_bytes(request.into_body()).await.map_err(|err| fail(StatusCode::BAD_REQUEST, &err.to_string()))?;
+    if bytes.is_empty() {
+        return Ok(T::default());
+    }
+    serde_json::from_slice(&bytes).map_err(|err| fail(StatusCode::BAD_REQUEST, &err.to_string()))
+}
+
+async fn kick<F: Fn(&Session) -> bool>(request: Request<Body>, filter: F) -> Result<Answer, Answer> {
+    let reason = optional_body::<KickBody>(request).await?.reason.unwrap_or(VIGILANT_LANG.load().player_kick.clone());
+    let kicked = session::kick(filter, &reason).await;
+
+    if kicked == 0 {
+        return Err(fail(StatusCode::NOT_FOUND, "No such session"));
+    }
+
+    Ok(ok(json!({ "kicked": kicked })))
+}
+
+async fn route(request: Request<Body>) -> Result<Answer, Answer> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let segments = path.trim_matches('/').split('/').collect::<Vec<&str>>();
+
+    match (method, segments.as_slice()) {
+        (Method::GET, ["stats"]) => Ok(ok(stats().await)),
+
+        (Method::GET, ["sessions"]) => Ok(ok(json!(SESSIONS.lock().await.values().collect::<Vec<_>>()))),
+        (Method::POST, ["sessions", id, "kick"]) => {
+            let id = id.parse::<u64>().map_err(|_| fail(StatusCode::BAD_REQUEST, "Invalid session id"))?;
+            kick(request, |v| v.id == id).await
+        }
+
+        (Method::GET, ["players"]) => Ok(ok(json!(*PLAYERS.lock().await))),
+        (Method::POST, ["players", username, "kick"]) => {
+            let username = username.to_string();
+            kick(request, |v| v.username.as_ref() == Some(&username)).await
+        }
+
+        (Method::GET, ["connections"]) => Ok(ok(json!(*CONNECTIONS.lock().await))),
+
+        (Method::GET, ["bans"]) => Ok(ok(json!(IP_BANLIST_DB.lock().unwrap().items()))),
+        (Method::POST, ["bans"]) => {
+            let ip = body::<IpBody>(request).await?.ip.to_string();
+            let kicked = guardian::ban(&ip).await;
+            info!("{}", coloriser!("Banned c(dark_blue){}c(reset) through the admin API", ip));
+            Ok(ok(json!({ "banned": ip, "kicked": kicked })))
+        }
+        (Method::DELETE, ["bans", ip]) => match guardian::unban(&guardian::normalize(ip).ok_or(fail(StatusCode::BAD_REQUEST, "Invalid IP"))?).map_err(|err| fail(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()))? {
+            true => Ok(ok(json!({ "unbanned": ip }))),
+            false => Err(fail(StatusCode::NOT_FOUND, "Not banned")),
+        },
+
+        (Method::GET, ["filters", list]) => {
+            let db = filter_db(list).ok_or(fail(StatusCode::NOT_FOUND, "Unknown list"))?;
+            Ok(ok(json!(db.lock().unwrap().items())))
+        }
+        (Method::POST, ["filters", list]) => {
+            let db = filter_db(list).ok_or(fail(StatusCode::NOT_FOUND, "Unknown list"))?;
+            let ip = guardian::ip_key(body::<IpBody>(request).await?.ip);
+            db.lock().unwrap().push(ip.clone()).map_err(|err| fail(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()))?;
+            Ok(ok(json!({ "added": ip })))
+        }
+        (Method::DELETE, ["filters", list, ip]) => {
+            let db = filter_db(list).ok_or(fail(StatusCode::NOT_FOUND, "Unknown list"))?;
+            let key = guardian::normalize(ip).ok_or(fail(StatusCode::BAD_REQUEST, "Invalid IP"))?;
+            let removed = db.lock().unwrap().remove(key).map_err(|err| fail(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()))?;
+            match removed {
+                true => Ok(ok(json!({ "removed": ip }))),
+                false => Err(fail(StatusCode::NOT_FOUND, "Not in the list")),
+            }
+        }
+
+        (Method::POST, ["reload"]) => match crate::reload() {
+            Ok(_) => Ok(ok(json!({ "reloaded": true }))),
+            Err(err) => Err(fail(StatusCode::UNPROCESSABLE_ENTITY, &format!("{err:#}"))),
+        },
+
+        (Method::PUT, ["attack-mode"]) => {
+            let active = body::<ToggleBody>(request).await?.active;
+            guardian::force_attack_mode(active);
+            Ok(ok(json!({ "attack_mode": active })))
+        }
+        (Method::PUT, ["maintenance"]) => {
+            let active = body::<ToggleBody>(request).await?.active;
+            shutdown::drain(active);
+            info!("{}", coloriser!("{} through the admin API", if active { "Started draining" } else { "Stopped draining" }));
+            Ok(ok(json!({ "maintenance": active })))
+        }
+
+        _ => Err(fail(StatusCode::NOT_FOUND, "Unknown endpoint")),
+    }
+}
+
+/// Compares in full, so the time taken doesn't hint at how much of the
+/// secret was right
+pub fn secret_eq(given: &str, secret: &str) -> bool {
+    given.len() == secret.len() && given.bytes().zip(secret.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn authorized(request: &Request<Body>) -> bool {
+    let given = request.headers().get("Authorization").and_then(|v| v.to_str().ok()).and_then(|v| v.strip_prefix("Bearer ")).unwrap_or("");
+
+    secret_eq(given, &VIGILANT_CONFIG.load().admin.token)
+}
+
+async fn handle(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let (status, value) = if authorized(&request) {
+        match route(request).await {
+            Ok(answer) | Err(answer) => answer,
+        }
+    } else {
+        fail(StatusCode::UNAUTHORIZED, "Missing or wrong bearer token")
+    };
+
+    Ok(Response::builder().status(status).header("Content-Type", "application/json").body(Body::from(value.to_string())).unwrap())
+}
+
+pub async fn serve() {
+    let config = VIGILANT_CONFIG.load();
+
+    if !config.admin.active {
+        return;
+    }
+
+    if config.admin.token.is_empty() {
+        error!("{}", coloriser!("Not starting the admin API, c(dark_purple)admin.tokenc(reset) is empty"));
+        return;
+    }
+
+    let address = match format!("{}:{}", config.admin.ip, config.admin.port).parse::<SocketAddr>() {
+        Ok(address) => address,
+        Err(err) => {
+            error!("{}", coloriser!("Invalid admin API address: {}", err.to_string()));
+            return;
+        }
+    };
+
+    let server = match Server::try_bind(&address) {
+        Ok(server) => server,
+        Err(err) => {
+            error!("{}", coloriser!("Failed to serve the admin API at c(on_blue) {} c(reset): {}", address, err.to_string()));
+            return;
+        }
+    };
+
+    info!("{}", coloriser!("Serving the admin API at c(on_blue) http://{} ", address));
+
+    if let Err(err) = server.serve(make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) })).await {
+        error!("{}", coloriser!("Admin API stopped: {}", err.to_string()));
+    }
+}
@@ -0,0 +1,182 @@
+//! Verification of suspicious logins. With `guardian.captcha.active`, a
+//! player that got through the gate but looks suspicious is logged in by the
+//! limbo instead of the server and has to type the code shown on its screen
+//! in the chat. A verified player is kicked to rejoin, its IP skipping the
+//! captcha for `guardian.captcha.remember` seconds, which also works in front
+//! of online mode servers. Usernames aren't remembered, anyone can send one.
+//!
+//! A player is suspicious when it was never verified and either
+//! `guardian.captcha.new_ip` is on, attack mode is on with
+//! `guardian.captcha.attack_mode`, or it didn't ping the server list first
+//! with `guardian.captcha.not_pinged`. A player the fingerprinting flagged
+//! has to solve one again, verified or not, if it comes back within
+//! `FLAGGED_FOR`.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::mpsc::UnboundedReceiver;
+use valence_protocol::bytes::BytesMut;
+use valence_protocol::text::Text;
+use valence_protocol::var_int::VarInt;
+use valence_protocol::Decode;
+
+use crate::events::{self, EventKind, RejectReason};
+use crate::file::{VERIFIED_DB, VIGILANT_CONFIG, VIGILANT_LANG};
+use crate::guardian::{ip_key, ATTACK_MODE};
+use crate::interceptor::gate;
+use crate::interceptor::pipe::read_var_int;
+use crate::limits::{self, Stage};
+use crate::macros::coloriser;
+use crate::packet::s2c;
+use crate::{limbo, make_bytes};
+
+const CHAT_COMMAND: i32 = 0x04;
+const CHAT_MESSAGE: i32 = 0x05;
+
+/// How long a flag waits for the IP to come back before it is forgotten
+const FLAGGED_FOR: Duration = Duration::from_secs(60 * 60);
+
+static FLAGGED: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Nothing that can be mistaken for something else, like 0 and O
+const CHARACTERS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/// Makes the next login from `ip` solve a captcha
+pub fn flag(ip: &str) {
+    let mut flagged = FLAGGED.lock().unwrap();
+    flagged.retain(|_, at| at.elapsed() < FLAGGED_FOR);
+    flagged.insert(ip.to_string(), Instant::now());
+}
+
+pub fn verified(ip: &str) -> bool {
+    VERIFIED_DB.lock().unwrap().has(ip)
+}
+
+/// Whether the player has to solve a captcha before it may join
+pub async fn required(address: SocketAddr) -> bool {
+    let config = VIGILANT_CONFIG.load();
+    let captcha = &config.guardian.captcha;
+    let ip = ip_key(address.ip());
+
+    if !captcha.active {
+        return false;
+    }
+
+    if FLAGGED.lock().unwrap().get(&ip).is_some_and(|at| at.elapsed() < FLAGGED_FOR) {
+        return true;
+    }
+
+    if verified(&ip) {
+        return false;
+    }
+
+    captcha.new_ip || (captcha.attack_mode && ATTACK_MODE.load(Ordering::Relaxed)) || (captcha.not_pinged && !gate::pinged(&ip).await)
+}
+
+fn code(length: usize) -> String {
+    let mut rng = rand::thread_rng();
+
+    (0..length).map(|_| CHARACTERS[rng.gen_range(0..CHARACTERS.len())] as char).collect()
+}
+
+/// Answers typed in the chat, or as a command, in the frames read so far
+fn answers(address: SocketAddr, buf: &mut BytesMut) -> anyhow::Result<Vec<String>> {
+    let mut answers = Vec::new();
+
+    while let Some((len, header)) = read_var_int(buf)? {
+        limits::frame(address, Stage::Play, len as usize)?;
+
+        if header + len as usize > buf.len() {
+            break;
+        }
+
+        let frame = buf.split_to(header + len as usize);
+        let mut data = &frame[header..];
+
+        if let CHAT_COMMAND | CHAT_MESSAGE = VarInt::decode(&mut data)?.0 {
+            answers.push(String::decode(&mut data)?);
+        }
+    }
+
+    Ok(answers)
+}
+
+/// Holds the player in the limbo until it types the code, runs out of
+/// attempts or time, or leaves, then kicks it either way
+pub async fn challenge(mut client_reader: OwnedReadHalf, mut client_writer: OwnedWriteHalf, address: SocketAddr, username: &str, protocol: i32, kick: &mut UnboundedReceiver<String>) -> anyhow::Result<()> {
+    let config = VIGILANT_CONFIG.load();
+    let captcha = &config.guardian.captcha;
+    let lang = VIGILANT_LANG.load();
+
+    let code = code(captcha.length.max(1));
+    let mut attempts = captcha.attempts.max(1);
+    let mut actionbar = lang.captcha_prompt.clone();
+    let mut shown = String::new();
+
+    client_writer.write_all(&limbo::join(username, protocol)).await?;
+    info!("{}", coloriser!("[/c(dark_blue){}c(reset)] Holding in the limbo until it solves a captcha", address));
+
+    let expired = tokio::time::sleep(Duration::from_secs(captcha.timeout.max(1)));
+    tokio::pin!(expired);
+
+    let mut keep_alive = tokio::time::interval(Duration::from_secs(10));
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    let mut buf = BytesMut::new();
+
+    let reason = 'challenge: loop {
+        tokio::select! {
+            read = client_reader.read_buf(&mut buf) => {
+                if read? == 0 {
+                    return Ok(());
+                }
+
+                for answer in answers(address, &mut buf)? {
+                    if answer.trim().trim_start_matches('/').eq_ignore_ascii_case(&code) {
+                        if let Err(err) = VERIFIED_DB.lock().unwrap().push(ip_key(address.ip()), captcha.remember) {
+                            warn!("{}", coloriser!("Failed to save the verified IPs: {}", err.to_string()));
+                        }
+                        FLAGGED.lock().unwrap().remove(&ip_key(address.ip()));
+                        info!("{}", coloriser!("[/c(dark_blue){}c(reset)] Solved the captcha", address));
+
+                        client_writer.write_all(&make_bytes!(s2c::Disconnect { reason: Text::from(lang.captcha_verified_kick.clone()) })).await?;
+                        return Ok(());
+                    }
+
+                    attempts -= 1;
+                    if attempts == 0 {
+                        break 'challenge "Failed the captcha";
+                    }
+
+                    actionbar = lang.captcha_wrong.replace("{attempts}", &attempts.to_string());
+                    limbo::show(&mut client_writer, &mut shown, &lang.captcha_title.replace("{code}", &code), &actionbar).await?;
+                }
+            }
+            Some(reason) = kick.recv() => {
+                client_writer.write_all(&make_bytes!(s2c::Disconnect { reason: Text::from(reason) })).await?;
+                return Ok(());
+            }
+            _ = &mut expired => break "Took too long to solve the captcha",
+            _ = keep_alive.tick() => {
+                client_writer.write_all(&make_bytes!(s2c::KeepAlive { id: chrono::Utc::now().timestamp_millis() })).await?;
+            }
+            _ = tick.tick() => {
+                limbo::show(&mut client_writer, &mut shown, &lang.captcha_title.replace("{code}", &code), &actionbar).await?;
+            }
+        }
+    };
+
+    info!("{}", coloriser!("[/c(dark_blue){}c(reset)] Rejected because: {}", address, reason));
+    events::emit(EventKind::Rejection { reason: RejectReason::Captcha, message: reason.to_string() }, address);
+
+    client_writer.write_all(&make_bytes!(s2c::Disconnect { reason: Text::from(lang.captcha_failed_kick.clone()) })).await?;
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use once_cell::sync::Lazy;
+
+use crate::file::config_file::Config;
+
+pub static ARGS: Lazy<Args> = Lazy::new(Args::parse);
+
+const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), " (", env!("VERGEN_GIT_BRANCH"), "-", env!("VERGEN_GIT_DESCRIBE"), "-", env!("VERGEN_BUILD_DATE"), ")");
+
+const LONG_VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "\nbranch: ", env!("VERGEN_GIT_BRANCH"), "\ncommit: ", env!("VERGEN_GIT_SHA"), "\ndescribe: ", env!("VERGEN_GIT_DESCRIBE"), "\nbuild date: ", env!("VERGEN_BUILD_DATE"), "\nrustc: ", env!("VERGEN_RUSTC_SEMVER"), "\ntarget: ", env!("VERGEN_CARGO_TARGET_TRIPLE"));
+
+#[derive(Parser)]
+#[command(name = "VigilantGuard", about = "A guarding proxy for Minecraft servers", version = VERSION, long_version = LONG_VERSION)]
+pub struct Args {
+    /// Path of the config file
+    #[arg(long, env = "VG_CONFIG", default_value = "./config.toml")]
+    pub config: PathBuf,
+
+    /// Path of the language file
+    #[arg(long, env = "VG_LANG", default_value = "./lang.toml")]
+    pub lang: PathBuf,
+
+    /// Directory holding the IP filter databases
+    #[arg(long, env = "VG_DATA_DIR", default_value = ".")]
+    pub data_dir: PathBuf,
+
+    /// Address to listen on, overrides `proxy.ip` and `proxy.port`
+    #[arg(long, env = "VG_LISTEN", value_name = "IP:PORT")]
+    pub listen: Option<String>,
+
+    /// Address of the backend server, overrides `server.ip` and `server.port`
+    #[arg(long, env = "VG_BACKEND", value_name = "IP:PORT")]
+    pub backend: Option<String>,
+
+    /// Overrides `proxy.ip`
+    #[arg(long, env = "VG_PROXY_IP")]
+    pub proxy_ip: Option<String>,
+
+    /// Overrides `proxy.port`
+    #[arg(long, env = "VG_PROXY_PORT")]
+    pub proxy_port: Option<u16>,
+
+    /// Overrides `server.ip`
+    #[arg(long, env = "VG_SERVER_IP")]
+    pub server_ip: Option<String>,
+
+    /// Overrides `server.port`
+    #[arg(long, env = "VG_SERVER_PORT")]
+    pub server_port: Option<u16>,
+
+    /// Log to stdout without the interactive console, for running as a service
+    #[arg(long, env = "VG_NO_CONSOLE")]
+    pub no_console: bool,
+
+    /// Validate the config and language files, then exit
+    #[arg(long)]
+    pub check_config: bool,
+}
+
+impl Args {
+    /// Applies the command line and environment overrides on top of a freshly
+    /// parsed config, so they survive a reload
+    pub fn apply(&self, config: &mut Config) -> anyhow::Result<()> {
+        if let Some(ip) = &self.proxy_ip {
+            config.proxy.ip = ip.clone();
+        }
+        if let Some(port) = self.proxy_port {
+            config.proxy.port = port;
+        }
+        if let Some(listen) = &self.listen {
+            (config.proxy.ip, config.proxy.port) = split_address(listen)?;
+        }
+
+        if let Some(ip) = &self.server_ip {
+            config.server.ip = ip.clone();
+        }
+        if let Some(port) = self.server_port {
+            config.server.port = port;
+        }
+        if let Some(backend) = &self.backend {
+            (config.server.ip, config.server.port) = split_address(backend)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn split_address(address: &str) -> anyhow::Result<(String, u16)> {
+    let (ip, port) = address.rsplit_once(":").ok_or(anyhow::anyhow!("{address:?} is not in the IP:PORT format"))?;
+
+    Ok((ip.to_string(), port.parse()?))
+}
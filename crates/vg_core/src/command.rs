@@ -0,0 +1,260 @@
+//! Console commands, shared by the terminal and RCON. A command answers with
+//! its output instead of logging it, so a remote caller gets it back.
+//!
+//! Every command is declared in [`COMMANDS`] with its arguments, which is all
+//! the usage lines, `help` and the terminal's tab completion are built from.
+
+use std::sync::atomic::Ordering;
+
+use futures::future::BoxFuture;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::file::{VIGILANT_CONFIG, VIGILANT_LANG};
+use crate::metrics::{BYTES_C2S, BYTES_S2C};
+use crate::{guardian, queue, session, shutdown, traffic, CONNECTIONS, PLAYERS, SESSIONS};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// Commands that only look at the proxy
+    Read,
+    /// Commands that change what the proxy does
+    Manage,
+}
+
+pub enum ArgKind {
+    Choice(&'static [&'static str]),
+    Command,
+    Player,
+    /// A player in the join queue
+    Waiting,
+    Ip,
+    /// Takes the rest of the line
+    Text,
+}
+
+pub struct Arg {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub required: bool,
+}
+
+pub struct Command {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub args: &'static [Arg],
+    pub permission: Permission,
+    pub help: &'static str,
+    run: fn(Vec<String>) -> BoxFuture<'static, String>,
+}
+
+impl Command {
+    pub fn usage(&self) -> String {
+        let mut usage = self.name.to_string();
+
+        for arg in self.args {
+            let name = match arg.kind {
+                ArgKind::Choice(options) => options.join("|"),
+                _ => arg.name.to_string(),
+            };
+
+            usage += &if arg.required { format!(" <{name}>") } else { format!(" [{name}]") };
+        }
+
+        usage
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.name == name || self.aliases.contains(&name)
+    }
+}
+
+pub static COMMANDS: Lazy<Vec<Command>> = Lazy::new(|| {
+    vec![
+        Command { name: "help", aliases: &[], args: &[Arg { name: "command", kind: ArgKind::Command, required: false }], permission: Permission::Read, help: "Lists the commands, or explains one of them", run: |args| Box::pin(help(args)) },
+        Command { name: "stop", aliases: &["exit"], args: &[], permission: Permission::Manage, help: "Disconnects everyone and shuts the proxy down, run it again to force it", run: |args| Box::pin(stop(args)) },
+        Command { name: "drain", aliases: &[], args: &[Arg { name: "mode", kind: ArgKind::Choice(&["on", "off"]), required: false }], permission: Permission::Manage, help: "Refuses new logins while the players already in keep playing", run: |args| Box::pin(drain(args)) },
+        Command { name: "reload", aliases: &[], args: &[], permission: Permission::Manage, help: "Reads the config and lang files again", run: |args| Box::pin(reload(args)) },
+        Command { name: "list", aliases: &[], args: &[Arg { name: "type", kind: ArgKind::Choice(&["connection", "conn", "player"]), required: true }], permission: Permission::Read, help: "Lists the open connections per IP, or the players, with their traffic", run: |args| Box::pin(list(args)) },
+        Command { name: "usage", aliases: &[], args: &[Arg { name: "type", kind: ArgKind::Choice(&["network", "net"]), required: true }], permission: Permission::Read, help: "Shows how much traffic went through the proxy", run: |args| Box::pin(usage(args)) },
+        Command { name: "kick", aliases: &[], args: &[Arg { name: "player", kind: ArgKind::Player, required: true }, Arg { name: "reason", kind: ArgKind::Text, required: false }], permission: Permission::Manage, help: "Disconnects a player", run: |args| Box::pin(kick(args)) },
+        Command { name: "ban", aliases: &[], args: &[Arg { name: "ip", kind: ArgKind::Ip, required: true }], permission: Permission::Manage, help: "Bans an IP and disconnects everyone on it", run: |args| Box::pin(ban(args)) },
+        Command { name: "unban", aliases: &[], args: &[Arg { name: "ip", kind: ArgKind::Ip, required: true }], permission: Permission::Manage, help: "Lifts the ban on an IP", run: |args| Box::pin(unban(args)) },
+        Command { name: "queue", aliases: &[], args: &[Arg { name: "action", kind: ArgKind::Choice(&["list", "next", "remove", "pause", "resume"]), required: false }, Arg { name: "player", kind: ArgKind::Waiting, required: false }], permission: Permission::Manage, help: "Shows the join queue, moves a player to its front or out of it, or pauses it", run: |args| Box::pin(queue(args)) },
+        Command { name: "attack", aliases: &[], args: &[Arg { name: "mode", kind: ArgKind::Choice(&["on", "off"]), required: true }], permission: Permission::Manage, help: "Holds attack mode on, or hands it back to the connection rate", run: |args| Box::pin(attack(args)) },
+    ]
+});
+
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|v| v.matches(name))
+}
+
+/// Splits a line into its words, the last argument keeping the rest of the
+/// line when it is [`ArgKind::Text`]
+fn parse(command: &Command, rest: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut rest = rest.trim();
+
+    for (index, arg) in command.args.iter().enumerate() {
+        if rest.is_empty() {
+            break;
+        }
+
+        if let ArgKind::Text = arg.kind {
+            if index == command.args.len() - 1 {
+                args.push(rest.to_string());
+                break;
+            }
+        }
+
+        let (word, remaining) = rest.split_once(' ').unwrap_or((rest, ""));
+        args.push(word.to_string());
+        rest = remaining.trim_start();
+    }
+
+    args
+}
+
+pub async fn execute(line: &str, permission: Permission) -> String {
+    let line = line.trim();
+    let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+
+    if name.is_empty() {
+        return String::new();
+    }
+
+    let Some(command) = find(name) else {
+        return format!("Unknown command {:?}, see help", name);
+    };
+
+    if command.permission > permission {
+        return format!("You are not allowed to use {:?}", command.name);
+    }
+
+    let args = parse(command, rest);
+
+    for (index, arg) in command.args.iter().enumerate() {
+        let Some(value) = args.get(index) else {
+            if arg.required {
+                return format!("Usage: {}", command.usage());
+            }
+            break;
+        };
+
+        match arg.kind {
+            ArgKind::Choice(options) if !options.contains(&value.as_str()) => return format!("Unknown {} {:?}, usage: {}", arg.name, value, command.usage()),
+            ArgKind::Ip if guardian::normalize(value).is_none() => return format!("Invalid IP {:?}", value),
+            _ => {}
+        }
+    }
+
+    (command.run)(args).await
+}
+
+async fn help(args: Vec<String>) -> String {
+    if let Some(name) = args.first() {
+        return match find(name) {
+            Some(command) if command.aliases.is_empty() => format!("Usage: {}\n{}", command.usage(), command.help),
+            Some(command) => format!("Usage: {}\n{}\nAliases: {}", command.usage(), command.help, command.aliases.join(", ")),
+            None => format!("Unknown command {:?}", name),
+        };
+    }
+
+    COMMANDS.iter().map(|v| format!("{} - {}", v.usage(), v.help)).collect::<Vec<_>>().join("\n")
+}
+
+async fn stop(_: Vec<String>) -> String {
+    // The shutdown reports its own progress
+    shutdown::stop();
+    String::new()
+}
+
+async fn drain(args: Vec<String>) -> String {
+    let active = args.first().is_none_or(|v| v == "on");
+    shutdown::drain(active);
+
+    if active { "Draining, new logins are refused until the proxy restarts or drain off is used" } else { "Stopped draining, new logins are accepted again" }.to_string()
+}
+
+async fn reload(_: Vec<String>) -> String {
+    match crate::reload() {
+        Ok(_) => "Reloaded the config and lang files".to_string(),
+        Err(err) => format!("Failed to reload: {err:#}"),
+    }
+}
+
+async fn list(args: Vec<String>) -> String {
+    if args[0] == "player" {
+        let players = PLAYERS.lock().await.keys().cloned().collect::<Vec<String>>();
+        let sessions = SESSIONS.lock().await;
+        let list = players.iter().filter_map(|v| sessions.get(v)).map(|v| format!("\n  {} ({}) {}, {}", v.username.as_deref().unwrap_or("?"), v.address, v.traffic.summary(), v.fingerprint.summary())).collect::<String>();
+        return format!("{} Players:{}", players.len(), list);
+    }
+
+    let connections = CONNECTIONS.lock().await;
+    let ips = traffic::ips();
+    let list = ips.iter().map(|(ip, traffic)| format!("\n  {} ({} connections) {}", ip, connections.get(ip).unwrap_or(&0), traffic.summary())).collect::<String>();
+    format!("{} Connections:{}", connections.values().sum::<usize>(), list)
+}
+
+async fn usage(_: Vec<String>) -> String {
+    format!("\x1b[1;32;42m ⬇ {}MB \x1b[0m\x1b[1;33;43m ⬆ {}MB ", BYTES_C2S.load(Ordering::Relaxed) as f64 / 1e+6, BYTES_S2C.load(Ordering::Relaxed) as f64 / 1e+6)
+}
+
+async fn kick(args: Vec<String>) -> String {
+    let reason = args.get(1).cloned().unwrap_or(VIGILANT_LANG.load().player_kick.clone());
+
+    match session::kick(|v| v.username.as_ref() == Some(&args[0]), &reason).await {
+        0 => format!("{} is not online", args[0]),
+        _ => format!("Kicked {}", args[0]),
+    }
+}
+
+async fn ban(args: Vec<String>) -> String {
+    let kicked = guardian::ban(&args[0]).await;
+    format!("Banned {}, disconnecting {} session(s)", args[0], kicked)
+}
+
+async fn unban(args: Vec<String>) -> String {
+    match guardian::unban(&args[0]) {
+        Ok(true) => format!("Unbanned {}", args[0]),
+        Ok(false) => format!("{} is not banned", args[0]),
+        Err(err) => format!("Unbanned {}, but failed to save the ban list: {}", args[0], err),
+    }
+}
+
+async fn attack(args: Vec<String>) -> String {
+    let active = args[0] == "on";
+    guardian::force_attack_mode(active);
+
+    if active { "Attack mode held on" } else { "Attack mode follows the connection rate again" }.to_string()
+}
+
+async fn queue(args: Vec<String>) -> String {
+    match (args.first().map(String::as_str), args.get(1)) {
+        (None | Some("list"), _) => {
+            let list = queue::with_waiting(|waiting| waiting.iter().enumerate().map(|(index, v)| format!("\n  #{} {} (tier {}) waiting for {}s", index + 1, v.username, v.tier, v.waited().as_secs())).collect::<String>());
+            let paused = if queue::paused() { ", paused" } else { "" };
+            format!("{}/{} Playing, {} Waiting{}:{}", queue::playing(), VIGILANT_CONFIG.load().queue.capacity, queue::waiting(), paused, list)
+        }
+        (Some("next" | "remove"), None) => "Usage: queue next|remove <player>".to_string(),
+        (Some("next"), Some(player)) => match queue::promote(player) {
+            true => format!("Moved {} to the front of the queue", player),
+            false => format!("{} is not in the queue", player),
+        },
+        (Some("remove"), Some(player)) => {
+            if !queue::remove(player) {
+                return format!("{} is not in the queue", player);
+            }
+
+            // Otherwise a player waiting in the limbo takes a new place
+            session::kick(|v| v.username.as_ref() == Some(player), &VIGILANT_LANG.load().player_kick).await;
+            format!("Removed {} from the queue", player)
+        }
+        (Some(action), _) => {
+            queue::pause(action == "pause");
+            if action == "pause" { "Paused the queue" } else { "Resumed the queue" }.to_string()
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! Machine-readable event stream for security tooling.
+//!
+//! Every event is one JSON object per line, written to `events.file` and/or
+//! sent as a UDP datagram to `events.socket`. Writing happens on its own
+//! thread so a slow disk or collector never holds up a connection, events
+//! that don't fit in its queue are dropped.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+
+use log::error;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::file::VIGILANT_CONFIG;
+use crate::macros::coloriser;
+use crate::session::Session;
+use crate::{guardian, metrics, SESSIONS};
+
+/// Events waiting for the writer before new ones get dropped
+const QUEUE: usize = 1024;
+
+static SENDER: Lazy<SyncSender<Event>> = Lazy::new(spawn_writer);
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    ConnectionOpened,
+    ConnectionClosed,
+    Handshake { server_address: String, server_port: u16, next_state: String },
+    StatusPing,
+    LoginAttempt,
+    LoginAllowed,
+    Queued { position: usize },
+    Rejection { reason: RejectReason, message: String },
+    BackendUp,
+    BackendDown,
+    AttackMode { active: bool, rate: usize },
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectReason {
+    PingNotCached,
+    ConnectionLimit,
+    Vpn,
+    Ban,
+    RateLimit,
+    Plugin,
+    Script,
+    Draining,
+    Offline,
+    Captcha,
+    Fingerprint,
+    Whitelist,
+}
+
+impl RejectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectReason::PingNotCached => "ping_not_cached",
+            RejectReason::ConnectionLimit => "connection_limit",
+            RejectReason::Vpn => "vpn",
+            RejectReason::Ban => "ban",
+            RejectReason::RateLimit => "rate_limit",
+            RejectReason::Plugin => "plugin",
+            RejectReason::Script => "script",
+            RejectReason::Draining => "draining",
+            RejectReason::Offline => "offline",
+            RejectReason::Captcha => "captcha",
+            RejectReason::Fingerprint => "fingerprint",
+            RejectReason::Whitelist => "whitelist",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Event {
+    timestamp: String,
+    #[serde(flatten)]
+    kind: EventKind,
+    ip: Option<String>,
+    username: Option<String>,
+    protocol_version: Option<i32>,
+    session_id: Option<u64>,
+    /// Session the writer still has to fill the fields above from
+    #[serde(skip)]
+    lookup: Option<SocketAddr>,
+}
+
+impl Event {
+    fn new(kind: EventKind, address: Option<SocketAddr>) -> Self {
+        Event { timestamp: chrono::Utc::now().to_rfc3339(), kind, ip: address.map(|v| guardian::canonical_ip(v.ip()).to_string()), username: None, protocol_version: None, session_id: None, lookup: None }
+    }
+
+    fn fill(&mut self, session: &Session) {
+        self.username = session.username.clone();
+        self.protocol_version = session.handshake.as_ref().map(|v| v.protocol_version);
+        self.session_id = Some(session.id);
+    }
+}
+
+/// Emits an event about a connection, the writer fills in what its session knows
+pub fn emit(kind: EventKind, address: SocketAddr) {
+    metrics::record(&kind);
+
+    if !VIGILANT_CONFIG.load().events.active {
+        return;
+    }
+
+    let mut event = Event::new(kind, Some(address));
+    event.lookup = Some(address);
+    send(event);
+}
+
+/// Emits an event about a session that is no longer in `SESSIONS`
+pub fn emit_session(kind: EventKind, session: &Session) {
+    metrics::record(&kind);
+
+    if !VIGILANT_CONFIG.load().events.active {
+        return;
+    }
+
+    let mut event = Event::new(kind, Some(session.address));
+    event.fill(session);
+    send(event);
+}
+
+/// Emits an event that isn't tied to any connection
+pub fn emit_global(kind: EventKind) {
+    metrics::record(&kind);
+
+    if !VIGILANT_CONFIG.load().events.active {
+        return;
+    }
+
+    send(Event::new(kind, None));
+}
+
+fn send(event: Event) {
+    if let Err(TrySendError::Full(_)) = SENDER.try_send(event) {
+        metrics::EVENTS_DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn spawn_writer() -> SyncSender<Event> {
+    let (sender, receiver) = mpsc::sync_channel::<Event>(QUEUE);
+
+    thread::Builder::new()
+        .name("events".to_string())
+        .spawn(move || {
+            let mut file = None;
+            let mut socket = None;
+            let mut opened = (String::new(), String::new());
+
+            while let Ok(first) = receiver.recv() {
+                let mut batch: Vec<Event> = std::iter::once(first).chain(receiver.try_iter()).collect();
+
+                // One lock for everything that queued up instead of one per event
+                if batch.iter().any(|v| v.lookup.is_some()) {
+                    let sessions = SESSIONS.blocking_lock();
+                    for event in batch.iter_mut() {
+                        if let Some(session) = event.lookup.take().and_then(|v| sessions.get(&v.to_string())) {
+                            event.fill(session);
+                        }
+                    }
+                }
+
+                let config = VIGILANT_CONFIG.load();
+                let events = &config.events;
+
+                // Reopened whenever a reload points them somewhere else
+                if opened != (events.file.clone(), events.socket.clone()) {
+                    opened = (events.file.clone(), events.socket.clone());
+                    file = open_file(&events.file);
+                    socket = open_socket(&events.socket);
+                }
+
+                for event in batch {
+                    let line = serde_json::to_string(&event).unwrap();
+
+                    if let Some(file) = file.as_mut() {
+                        if let Err(err) = writeln!(file, "{line}") {
+                            error!("{}", coloriser!("Failed to write the event log: {}", err.to_string()));
+                        }
+                    }
+
+                    if let Some((socket, address)) = socket.as_ref() {
+                        let _ = socket.send_to(line.as_bytes(), address);
+                    }
+                }
+
+                if let Some(file) = file.as_mut() {
+                    if let Err(err) = file.flush() {
+                        error!("{}", coloriser!("Failed to write the event log: {}", err.to_string()));
+                    }
+                }
+            }
+        })
+        .unwrap();
+
+    sender
+}
+
+fn open_file(path: &str) -> Option<BufWriter<File>> {
+    if path.is_empty() {
+        return None;
+    }
+
+    if let Some(parent) = Path::new(path).parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+
+    match File::options().create(true).append(true).open(path) {
+        Ok(file) => Some(BufWriter::new(file)),
+        Err(err) => {
+            error!("{}", coloriser!("Failed to open the event log c(dark_purple){}c(reset): {}", path, err.to_string()));
+            None
+        }
+    }
+}
+
+fn open_socket(address: &str) -> Option<(UdpSocket, SocketAddr)> {
+    if address.is_empty() {
+        return None;
+    }
+
+    let open = || -> anyhow::Result<(UdpSocket, SocketAddr)> {
+        let address: SocketAddr = address.parse()?;
+        let socket = UdpSocket::bind(if address.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" })?;
+        Ok((socket, address))
+    };
+
+    match open() {
+        Ok(socket) => Some(socket),
+        Err(err) => {
+            error!("{}", coloriser!("Failed to open the event socket c(dark_purple){}c(reset): {}", address, err.to_string()));
+            None
+        }
+    }
+}
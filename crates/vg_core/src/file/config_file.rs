@@ -1,26 +1,62 @@
-use std::fs::{
-    File, {self},
-};
-use std::io::{Read, Write};
+use std::collections::HashMap;
+use std::fs;
 
+use anyhow::Context;
+use log::LevelFilter;
 use serde::{Deserialize, Serialize};
 
+use crate::cli::ARGS;
+use crate::command::Permission;
+
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub colorize: bool,
+    pub watch_files: bool,
     pub proxy: ProxyConfig,
     pub server: ServerConfig,
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
     pub guardian: GuardianConfig,
+    pub plugins: PluginConfig,
+    pub scripts: ScriptConfig,
+    pub shutdown: ShutdownConfig,
+    pub logging: LoggingConfig,
+    pub events: EventsConfig,
+    pub metrics: MetricsConfig,
+    pub admin: AdminConfig,
+    pub rcon: RconConfig,
+    pub limbo: LimboConfig,
+    pub queue: QueueConfig,
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ProxyConfig {
     pub ip: String,
     pub port: u16,
+    pub extra_ips: Vec<String>,
+    pub ipv6_prefix: u8,
+    pub proxy_protocol: bool,
+    pub trusted_proxies: Vec<String>,
     pub forwarder: ProxyForwarder,
+    pub status_cache: StatusCache,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub name: String,
+    pub ip: String,
+    pub port: u16,
+    pub server: String,
+    pub ip_forward: Option<bool>,
+    pub proxy_protocol: bool,
+    pub profile: String,
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ProxyForwarder {
     pub ip_forward: bool,
     pub ping_forward: bool,
@@ -28,62 +64,359 @@ pub struct ProxyForwarder {
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatusCache {
+    pub active: bool,
+    pub ttl: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ServerConfig {
     pub ip: String,
     pub port: u16,
+    pub connect_timeout: u64,
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct GuardianConfig {
     pub ping_protection: PingProtection,
     pub ip_connection_limit: IPLimiter,
+    pub rate_limit: RateLimiter,
     pub vpn_filter: VPNFilter,
+    pub vpn_lookup: VpnLookup,
+    pub attack_mode: AttackMode,
+    pub bandwidth: BandwidthLimiter,
+    pub timeouts: Timeouts,
+    pub captcha: Captcha,
+    pub fingerprint: Fingerprint,
+    pub limits: Limits,
+    pub whitelist: Whitelist,
+    pub attack_profile: String,
+    pub hostnames: HashMap<String, String>,
+    #[serde(default)]
+    pub profiles: HashMap<String, GuardianProfile>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// What of `[guardian]` a profile can set differently
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GuardianProfile {
+    pub ping_protection: PingProtection,
+    pub ip_connection_limit: IPLimiter,
+    pub rate_limit: RateLimiter,
+    pub vpn_filter: VPNFilter,
+    pub bandwidth: BandwidthLimiter,
+    pub whitelist: Whitelist,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct PingProtection {
     pub active: bool,
     pub reset_interval: u64,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct IPLimiter {
     pub active: bool,
     pub limit: usize,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimiter {
+    pub active: bool,
+    pub logins: usize,
+    pub window: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct VPNFilter {
     pub active: bool,
 }
 
-impl Config {
-    pub fn save(&self) {
-        if let Ok(_) = fs::read("./config.toml") {
-            fs::rename("./config.toml", "./config.toml.bak").unwrap();
-        }
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VpnLookup {
+    pub ttl: u64,
+    pub timeout: u64,
+    pub concurrency: usize,
+    pub fallback: VpnFallback,
+    pub prewarm: bool,
+}
+
+/// The verdict of a lookup the provider didn't answer, [`VpnFallback::Allow`]
+/// unless set otherwise
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum VpnFallback {
+    Allow,
+    Deny,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AttackMode {
+    pub active: bool,
+    pub threshold: usize,
+    pub duration: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BandwidthLimiter {
+    pub active: bool,
+    pub connection_limit: u64,
+    pub ip_limit: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Whitelist {
+    pub active: bool,
+    pub players: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Timeouts {
+    pub handshake: u64,
+    pub status: u64,
+    pub login: u64,
+    pub idle: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    pub active: bool,
+    pub handshake: usize,
+    pub status: usize,
+    pub login: usize,
+    pub play: usize,
+    pub buffer: usize,
+    pub hostname: usize,
+    pub username: usize,
+    pub ban_after: u32,
+    pub ban_duration: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Captcha {
+    pub active: bool,
+    pub new_ip: bool,
+    pub attack_mode: bool,
+    pub not_pinged: bool,
+    pub kick_unsupported: bool,
+    pub length: usize,
+    pub attempts: u32,
+    pub timeout: u64,
+    pub remember: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fingerprint {
+    pub active: bool,
+    pub window: u64,
+    pub threshold: u32,
+    pub action: FingerprintAction,
+    pub min_reply: u64,
+    pub no_brand: u32,
+    pub no_settings: u32,
+    pub no_movement: u32,
+    pub keep_alive: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FingerprintAction {
+    Log,
+    Kick,
+    Ban,
+    Captcha,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptConfig {
+    pub active: bool,
+    pub directory: String,
+    pub on_error: ScriptErrorVerdict,
+    pub max_operations: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptErrorVerdict {
+    Allow,
+    Deny,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShutdownConfig {
+    pub timeout: u64,
+}
 
-        let mut file = File::options().read(false).write(true).create(true).open("./config.toml").unwrap();
-        let config = toml::to_string_pretty(&self).unwrap();
-        file.write_all(config.as_bytes()).unwrap();
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: LevelFilter,
+    pub modules: HashMap<String, LevelFilter>,
+    pub file: LogFileConfig,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogFileConfig {
+    pub active: bool,
+    pub path: String,
+    pub max_size: u64,
+    pub interval: u64,
+    pub keep: u32,
+    pub gzip: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EventsConfig {
+    pub active: bool,
+    pub file: String,
+    pub socket: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub active: bool,
+    pub ip: String,
+    pub port: u16,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    pub active: bool,
+    pub ip: String,
+    pub port: u16,
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RconConfig {
+    pub active: bool,
+    pub ip: String,
+    pub port: u16,
+    pub password: String,
+    pub permission: Permission,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimboConfig {
+    pub active: bool,
+    pub min_protocol: i32,
+    pub max_protocol: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueueConfig {
+    pub active: bool,
+    pub capacity: usize,
+    pub limbo: bool,
+    pub reconnect: u64,
+    pub keep: u64,
+    pub priority: HashMap<String, u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PluginConfig {
+    pub active: bool,
+    pub directory: String,
+    pub timeout: u64,
+    pub memory: usize,
+    #[serde(default)]
+    pub list: HashMap<String, PluginEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PluginEntry {
+    pub active: bool,
+    pub config: toml::Table,
+}
+
+/// The embedded default config, which also fills in whatever a config file
+/// leaves out
+impl Default for Config {
+    fn default() -> Self {
+        toml::from_str(DEFAULT_CONFIG).expect("the default config is valid")
     }
 }
 
-pub fn parse() -> Config {
-    let mut file = File::options().read(true).write(true).create(true).open("./config.toml").unwrap();
-    let mut buf = String::new();
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self { name: String::new(), ip: "0.0.0.0".to_string(), port: 25565, server: String::new(), ip_forward: None, proxy_protocol: false, profile: String::new() }
+    }
+}
+
+impl Default for PluginEntry {
+    fn default() -> Self {
+        Self { active: false, config: toml::Table::new() }
+    }
+}
+
+/// The parts of `[guardian]` a profile sets on its own
+const PROFILE_SECTIONS: [&str; 6] = ["ping_protection", "ip_connection_limit", "rate_limit", "vpn_filter", "bandwidth", "whitelist"];
 
-    file.read_to_string(&mut buf).unwrap();
+/// Reads and validates the config file, taking what it leaves out from the
+/// default one. The error carries the line and column of a syntax error, or
+/// the key of a wrong value.
+pub fn load() -> anyhow::Result<Config> {
+    let path = ARGS.config.display();
+    let buf = fs::read_to_string(&ARGS.config).with_context(|| format!("Failed to read {path}"))?;
 
-    let config: Result<Config, toml::de::Error> = toml::from_str(&buf);
+    let mut table: toml::Table = toml::from_str(&buf).with_context(|| format!("Invalid {path}"))?;
+    let defaults: toml::Table = toml::from_str(DEFAULT_CONFIG)?;
+    super::merge(&mut table, &defaults);
 
-    if let Ok(config) = config {
-        return config;
-    } else {
-        let default: Config = toml::from_str(&String::from_utf8_lossy(DEFAULT_CONFIG.as_bytes())).unwrap();
-        default.save();
-        return default;
+    // The sections a profile leaves out are the default ones of `[guardian]`, which are off
+    let guardian = defaults.get("guardian").and_then(toml::Value::as_table).context("The default config has no [guardian]")?;
+    let sections = guardian.iter().filter(|(key, _)| PROFILE_SECTIONS.contains(&key.as_str())).map(|(key, value)| (key.clone(), value.clone())).collect::<toml::Table>();
+    if let Some(profiles) = table.get_mut("guardian").and_then(|v| v.get_mut("profiles")).and_then(toml::Value::as_table_mut) {
+        for profile in profiles.iter_mut().filter_map(|(_, v)| v.as_table_mut()) {
+            super::merge(profile, &sections);
+        }
     }
+
+    let mut config: Config = table.try_into().with_context(|| format!("Invalid {path}"))?;
+    ARGS.apply(&mut config)?;
+
+    let guardian = &config.guardian;
+    let selected = config.listeners.iter().map(|v| &v.profile).chain(guardian.hostnames.values()).chain([&guardian.attack_profile]);
+    if let Some(unknown) = selected.filter(|v| !v.is_empty()).find(|v| !guardian.profiles.contains_key(*v)) {
+        anyhow::bail!("Invalid {path}: there is no guardian profile named {unknown:?}");
+    }
+
+    Ok(config)
+}
+
+/// Loads the config file, writing out the default one first if there is none
+pub fn parse() -> anyhow::Result<Config> {
+    if fs::metadata(&ARGS.config).is_err() {
+        fs::write(&ARGS.config, DEFAULT_CONFIG).with_context(|| format!("Failed to write {}", ARGS.config.display()))?;
+    }
+
+    load()
 }
 
 const DEFAULT_CONFIG: &str = include_str!("./default/config.toml");
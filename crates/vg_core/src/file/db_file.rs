@@ -0,0 +1,41 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+
+/// The file behind an IP database, its entries separated by `|`. A database
+/// that wasn't loaded yet has none and is only kept in memory.
+#[derive(Default)]
+pub struct DbFile(Option<File>);
+
+impl DbFile {
+    /// Opens `path`, creating it and its directory when missing, along with
+    /// the entries it holds
+    pub fn open(path: &Path) -> io::Result<(Self, Vec<String>)> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = File::options().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut buf = String::new();
+        file.read_to_string(&mut buf)?;
+
+        let entries = buf.split('|').filter(|v| !v.is_empty()).map(str::to_string).collect();
+        Ok((Self(Some(file)), entries))
+    }
+
+    /// Replaces the entries in the file
+    pub fn write<S: AsRef<str>>(&mut self, entries: impl Iterator<Item = S>) -> io::Result<()> {
+        let Some(file) = self.0.as_mut() else {
+            return Ok(());
+        };
+
+        let val = entries.map(|v| v.as_ref().to_string()).collect::<Vec<_>>().join("|");
+        file.set_len(0)?;
+        file.rewind()?;
+        file.write_all(val.as_bytes())
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.0.as_ref().map_or(Ok(()), File::sync_all)
+    }
+}
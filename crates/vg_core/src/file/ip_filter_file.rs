@@ -1,33 +1,49 @@
-use std::fs::File;
-use std::io::{Read, Seek, Write};
+use std::io;
+use std::path::Path;
 
+use super::db_file::DbFile;
+use crate::guardian;
+
+#[derive(Default)]
 pub struct IpFilter {
-    file: File,
+    file: DbFile,
     items: Vec<String>,
 }
 
 impl IpFilter {
-    pub fn load(out_file: &str) -> Self {
-        let mut file = File::options().read(true).write(true).create(true).open(out_file).unwrap();
-        let mut buf = String::new();
-        file.read_to_string(&mut buf).unwrap();
-        let items: Vec<String> = buf.split("|").into_iter().map(|v| v.to_string()).collect();
-        Self { file, items }
+    pub fn load<P: AsRef<Path>>(out_file: P) -> io::Result<Self> {
+        let (file, entries) = DbFile::open(out_file.as_ref())?;
+        // Keyed again, in case `proxy.ipv6_prefix` changed since they were saved
+        let mut items: Vec<String> = Vec::new();
+        for item in entries.into_iter().map(|v| guardian::normalize(&v).unwrap_or(v)) {
+            if !items.contains(&item) {
+                items.push(item);
+            }
+        }
+        Ok(Self { file, items })
     }
 
-    pub fn push<S: Into<String>>(&mut self, item: S) {
+    pub fn push<S: Into<String>>(&mut self, item: S) -> io::Result<()> {
         let item: String = item.into();
         if !self.has(&item) {
             self.items.push(item);
-            self.update();
+            self.update()?;
         }
+        Ok(())
     }
 
-    pub fn remove<S: Into<String>>(&mut self, item: S) {
+    pub fn remove<S: Into<String>>(&mut self, item: S) -> io::Result<bool> {
         let item: String = item.into();
         if let Some(index) = self.items.iter().enumerate().find_map(|(i, v)| if v == &item { Some(i) } else { None }) {
             self.items.remove(index);
+            self.update()?;
+            return Ok(true);
         }
+        Ok(false)
+    }
+
+    pub fn items(&self) -> &[String] {
+        &self.items
     }
 
     pub fn has<S: Into<String>>(&self, item: S) -> bool {
@@ -39,11 +55,13 @@ impl IpFilter {
         }
     }
 
-    pub fn update(&mut self) {
-        let val = self.items.join("|");
-        let buf = val.as_bytes();
-        self.file.set_len(0).unwrap();
-        self.file.rewind().unwrap();
-        self.file.write(buf).unwrap();
+    pub fn update(&mut self) -> io::Result<()> {
+        self.file.write(self.items.iter())
+    }
+
+    /// Writes out the items, including removals, and syncs the file to disk
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.update()?;
+        self.file.sync()
     }
 }
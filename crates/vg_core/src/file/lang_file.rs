@@ -1,52 +1,69 @@
-use std::fs::{
-    File, {self},
-};
-use std::io::{Read, Write};
+use std::fs;
 
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
 
+use crate::cli::ARGS;
+
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Lang {
     pub player_ping_not_cached_kick: String,
     pub player_connection_more_kick: String,
+    pub player_rate_limited_kick: String,
     pub player_ip_blacklisted_kick: String,
+    pub player_script_kick: String,
+    pub player_not_whitelisted_kick: String,
+    pub player_banned_kick: String,
+    pub player_kick: String,
     pub server_offline_motd: String,
     pub server_version_name: String,
     pub server_offline_kick: String,
+    pub server_restarting_motd: String,
+    pub server_restarting_kick: String,
+    pub server_shutdown_kick: String,
     pub server_motd: String,
+    pub limbo_title: String,
+    pub limbo_actionbar: String,
+    pub limbo_transfer_kick: String,
+    pub queue_title: String,
+    pub queue_actionbar: String,
+    pub queue_kick: String,
+    pub captcha_title: String,
+    pub captcha_prompt: String,
+    pub captcha_wrong: String,
+    pub captcha_verified_kick: String,
+    pub captcha_failed_kick: String,
+    pub captcha_unsupported_kick: String,
+    pub fingerprint_kick: String,
+    pub fingerprint_captcha_kick: String,
 }
 
-impl Lang {
-    pub fn save(&self) {
-        if let Ok(_) = fs::read("./lang.toml") {
-            fs::rename("./lang.toml", "./lang.toml.bak").unwrap();
-        }
-
-        let mut file = File::options().read(false).write(true).create(true).open("./lang.toml").unwrap();
-        let config = toml::to_string_pretty(&self).unwrap();
-        file.write_all(config.as_bytes()).unwrap();
+/// The embedded default lang file, which also fills in whatever a lang file
+/// leaves out
+impl Default for Lang {
+    fn default() -> Self {
+        toml::from_str(&DEFAULT_LANG.colorize()).expect("the default lang file is valid")
     }
 }
 
-pub fn parse() -> Lang {
-    log::info!("Loading language file");
-    let mut file = File::options().read(true).write(true).create(true).open("./lang.toml").unwrap();
-    let mut buf = String::new();
-
-    file.read_to_string(&mut buf).unwrap();
+pub fn load() -> anyhow::Result<Lang> {
+    let path = ARGS.lang.display();
+    let buf = fs::read_to_string(&ARGS.lang).with_context(|| format!("Failed to read {path}"))?.colorize();
 
-    buf = buf.colorize();
+    let mut table: toml::Table = toml::from_str(&buf).with_context(|| format!("Invalid {path}"))?;
+    super::merge(&mut table, &toml::from_str(&DEFAULT_LANG.colorize())?);
 
-    let config: Result<Lang, toml::de::Error> = toml::from_str(&buf);
+    table.try_into().with_context(|| format!("Invalid {path}"))
+}
 
-    if let Ok(config) = config {
-        return config;
-    } else {
-        let string = String::from_utf8_lossy(DEFAULT_LANG.as_bytes()).to_string();
-        let default: Lang = toml::from_str(&string.colorize()).unwrap();
-        default.save();
-        return default;
+/// Loads the lang file, writing out the default one first if there is none
+pub fn parse() -> anyhow::Result<Lang> {
+    if fs::metadata(&ARGS.lang).is_err() {
+        fs::write(&ARGS.lang, DEFAULT_LANG).with_context(|| format!("Failed to write {}", ARGS.lang.display()))?;
     }
+
+    load()
 }
 
 const COLOR_LIST: [char; 20] = ['0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'a', 'b', 'c', 'd', 'e', 'f', 'l', 'n', 'o', 'k'];
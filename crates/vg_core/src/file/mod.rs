@@ -1,15 +1,111 @@
-mod config_file;
-mod ip_filter_file;
+pub mod config_file;
+pub mod db_file;
+pub mod ip_filter_file;
 pub mod lang_file;
+pub mod verified_file;
 
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
 use once_cell::sync::Lazy;
 
 use self::config_file::Config;
 use self::ip_filter_file::IpFilter;
 use self::lang_file::Lang;
+use self::verified_file::VerifiedList;
+use crate::cli::ARGS;
+
+/// The defaults until [`init`] loads the files
+pub static VIGILANT_CONFIG: Lazy<ArcSwap<Config>> = Lazy::new(|| ArcSwap::from_pointee(Config::default()));
+pub static VIGILANT_LANG: Lazy<ArcSwap<Lang>> = Lazy::new(|| ArcSwap::from_pointee(Lang::default()));
+
+/// Empty and in memory only until [`load_databases`] loads them
+pub static IP_BLACKLIST_DB: Lazy<Mutex<IpFilter>> = Lazy::new(|| Mutex::new(IpFilter::default()));
+pub static IP_WHITELIST_DB: Lazy<Mutex<IpFilter>> = Lazy::new(|| Mutex::new(IpFilter::default()));
+pub static IP_BANLIST_DB: Lazy<Mutex<IpFilter>> = Lazy::new(|| Mutex::new(IpFilter::default()));
+pub static VERIFIED_DB: Lazy<Mutex<VerifiedList>> = Lazy::new(|| Mutex::new(VerifiedList::default()));
+
+/// Reads both files, writing out the default ones that are missing, before
+/// anything else looks at them
+pub fn init() -> anyhow::Result<()> {
+    VIGILANT_CONFIG.store(Arc::new(config_file::parse()?));
+    VIGILANT_LANG.store(Arc::new(lang_file::parse()?));
+    Ok(())
+}
+
+/// Reads the databases in `--data-dir`, after [`init`] as they are keyed with
+/// `proxy.ipv6_prefix`
+pub fn load_databases() -> anyhow::Result<()> {
+    *IP_BLACKLIST_DB.lock().unwrap() = IpFilter::load(ARGS.data_dir.join("ip_blacklist.db.txt")).context("Failed to load the IP blacklist")?;
+    *IP_WHITELIST_DB.lock().unwrap() = IpFilter::load(ARGS.data_dir.join("ip_whitelist.db.txt")).context("Failed to load the IP whitelist")?;
+    *IP_BANLIST_DB.lock().unwrap() = IpFilter::load(ARGS.data_dir.join("ip_banlist.db.txt")).context("Failed to load the ban list")?;
+    *VERIFIED_DB.lock().unwrap() = VerifiedList::load(ARGS.data_dir.join("verified.db.txt")).context("Failed to load the verified IPs")?;
+    Ok(())
+}
+
+/// Fills in what `table` leaves out from `defaults`, going into the tables
+/// both of them have
+fn merge(table: &mut toml::Table, defaults: &toml::Table) {
+    for (key, default) in defaults {
+        match (table.get_mut(key), default) {
+            (Some(toml::Value::Table(table)), toml::Value::Table(default)) => merge(table, default),
+            (Some(_), _) => {}
+            (None, default) => drop(table.insert(key.clone(), default.clone())),
+        }
+    }
+}
+
+/// Reads both files again and swaps them in only when both of them are valid,
+/// returning the config that was active before
+pub fn reload() -> anyhow::Result<Arc<Config>> {
+    let mut config = config_file::load()?;
+    let lang = lang_file::load()?;
+
+    // Everything kept per IP is keyed on it, so it only changes on a restart
+    let prefix = VIGILANT_CONFIG.load().proxy.ipv6_prefix;
+    if config.proxy.ipv6_prefix != prefix {
+        log::warn!("proxy.ipv6_prefix only changes on a restart, keeping {}", prefix);
+        config.proxy.ipv6_prefix = prefix;
+    }
+
+    VIGILANT_LANG.store(Arc::new(lang));
+    Ok(VIGILANT_CONFIG.swap(Arc::new(config)))
+}
+
+/// Validates both files without touching the running state, for `--check-config`
+pub fn check() -> bool {
+    let mut valid = true;
+
+    for (path, result) in [(&ARGS.config, config_file::load().map(|_| ())), (&ARGS.lang, lang_file::load().map(|_| ()))] {
+        match result {
+            Ok(_) => println!("{} is valid", path.display()),
+            Err(err) => {
+                println!("{err:#}");
+                valid = false;
+            }
+        }
+    }
+
+    valid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedded_files_are_valid() {
+        let _ = Config::default();
+        let _ = Lang::default();
+    }
 
-pub static VIGILANT_CONFIG: Lazy<Config> = Lazy::new(|| config_file::parse());
-pub static VIGILANT_LANG: Lazy<Lang> = Lazy::new(|| lang_file::parse());
+    #[test]
+    fn merge_fills_in_what_is_left_out() {
+        let mut table: toml::Table = toml::from_str("a = 1\n[b]\nc = 2\n[d]\ne = [3]").unwrap();
+        let defaults: toml::Table = toml::from_str("a = 0\nf = 4\n[b]\nc = 0\ng = 5\n[d]\ne = [0, 0]").unwrap();
+        merge(&mut table, &defaults);
 
-pub static mut IP_BLACKLIST_DB: Lazy<IpFilter> = Lazy::new(|| IpFilter::load("ip_blacklist.db.txt"));
-pub static mut IP_WHITELIST_DB: Lazy<IpFilter> = Lazy::new(|| IpFilter::load("ip_whitelist.db.txt"));
+        assert_eq!(table, toml::from_str("a = 1\nf = 4\n[b]\nc = 2\ng = 5\n[d]\ne = [3]").unwrap());
+    }
+}
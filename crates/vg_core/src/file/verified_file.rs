@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use super::db_file::DbFile;
+use crate::guardian;
+
+/// Like [`super::ip_filter_file::IpFilter`], but every item is only kept
+/// until its own expiry, stored next to it as a unix timestamp
+#[derive(Default)]
+pub struct VerifiedList {
+    file: DbFile,
+    items: HashMap<String, i64>,
+}
+
+impl VerifiedList {
+    pub fn load<P: AsRef<Path>>(out_file: P) -> io::Result<Self> {
+        let (file, entries) = DbFile::open(out_file.as_ref())?;
+        // Keyed again, in case `proxy.ipv6_prefix` changed since they were saved
+        let items = entries.iter().filter_map(|v| v.rsplit_once('=')).filter_map(|(item, until)| Some((guardian::normalize(item).unwrap_or_else(|| item.to_string()), until.parse().ok()?))).collect();
+        Ok(Self { file, items })
+    }
+
+    /// Keeps `item` for `seconds` from now
+    pub fn push<S: Into<String>>(&mut self, item: S, seconds: u64) -> io::Result<()> {
+        self.items.insert(item.into(), chrono::Utc::now().timestamp() + seconds as i64);
+        self.update()
+    }
+
+    pub fn has<S: Into<String>>(&self, item: S) -> bool {
+        self.items.get(&item.into()).is_some_and(|until| *until > chrono::Utc::now().timestamp())
+    }
+
+    pub fn update(&mut self) -> io::Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        self.items.retain(|_, until| *until > now);
+
+        self.file.write(self.items.iter().map(|(item, until)| format!("{item}={until}")))
+    }
+
+    /// Writes out the items that haven't expired and syncs the file to disk
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.update()?;
+        self.file.sync()
+    }
+}
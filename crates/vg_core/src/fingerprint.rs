@@ -0,0 +1,167 @@
+//! Scoring of how much a session behaves like a real client, from what it
+//! sends in its first `guardian.fingerprint.window` seconds of play. A real
+//! client sends its settings and brand right after joining, moves, and
+//! answers every keep alive; each of those that is missing adds its weight to
+//! the score, and `guardian.fingerprint.action` is taken once the score
+//! reaches `guardian.fingerprint.threshold`.
+//!
+//! The pipe can only read sessions that aren't encrypted and whose version's
+//! packet ids are known, the others are never scored.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde::Serialize;
+use valence_protocol::var_int::VarInt;
+use valence_protocol::Decode;
+
+use crate::events::{self, EventKind, RejectReason};
+use crate::file::config_file::FingerprintAction;
+use crate::file::{VIGILANT_CONFIG, VIGILANT_LANG};
+use crate::macros::coloriser;
+use crate::packet::{PacketDirection, PlayIds};
+use crate::{captcha, guardian, session};
+
+/// How long a keep alive may go unanswered before it counts against the
+/// client, a real one answers within its next tick
+const UNANSWERED: Duration = Duration::from_secs(15);
+
+#[derive(Default, Serialize)]
+pub struct Fingerprint {
+    pub brand: Mutex<Option<String>>,
+    pub settings: AtomicBool,
+    pub moved: AtomicBool,
+    pub keep_alive_replies: AtomicU32,
+    /// Replies to keep alives that were never sent, or came back too fast
+    pub keep_alive_unexpected: AtomicU32,
+    /// Set once the session was judged
+    pub score: Mutex<Option<u32>>,
+    #[serde(skip)]
+    sent: Mutex<Vec<(i64, Instant)>>,
+}
+
+impl Fingerprint {
+    /// Takes note of a play packet, `data` starting at its id
+    pub fn observe(&self, direction: &PacketDirection, ids: &PlayIds, mut data: &[u8]) -> anyhow::Result<()> {
+        let id = VarInt::decode(&mut data)?.0;
+
+        match direction {
+            PacketDirection::S2C if id == ids.keep_alive_s2c => self.sent.lock().unwrap().push((i64::decode(&mut data)?, Instant::now())),
+            PacketDirection::S2C => {}
+            PacketDirection::C2S if id == ids.client_information => self.settings.store(true, Ordering::Relaxed),
+            PacketDirection::C2S if id == ids.position || id == ids.position + 1 => self.moved.store(true, Ordering::Relaxed),
+            PacketDirection::C2S if id == ids.plugin_message => {
+                if let "minecraft:brand" | "MC|Brand" = String::decode(&mut data)?.as_str() {
+                    *self.brand.lock().unwrap() = Some(String::decode(&mut data)?);
+                }
+            }
+            PacketDirection::C2S if id == ids.keep_alive_c2s => {
+                let id = i64::decode(&mut data)?;
+                let min_reply = Duration::from_millis(VIGILANT_CONFIG.load().guardian.fingerprint.min_reply);
+
+                let mut sent = self.sent.lock().unwrap();
+                match sent.iter().position(|(v, _)| *v == id) {
+                    Some(index) if sent.remove(index).1.elapsed() >= min_reply => self.keep_alive_replies.fetch_add(1, Ordering::Relaxed),
+                    _ => self.keep_alive_unexpected.fetch_add(1, Ordering::Relaxed),
+                };
+            }
+            PacketDirection::C2S => {}
+        }
+
+        Ok(())
+    }
+
+    /// What the client didn't do so far, with what each of them weighs
+    pub fn flags(&self) -> Vec<(&'static str, u32)> {
+        let config = VIGILANT_CONFIG.load();
+        let weights = &config.guardian.fingerprint;
+        let mut flags = Vec::new();
+
+        if self.brand.lock().unwrap().is_none() {
+            flags.push(("no brand", weights.no_brand));
+        }
+
+        if !self.settings.load(Ordering::Relaxed) {
+            flags.push(("no settings", weights.no_settings));
+        }
+
+        if !self.moved.load(Ordering::Relaxed) {
+            flags.push(("no movement", weights.no_movement));
+        }
+
+        let unanswered = self.sent.lock().unwrap().iter().any(|(_, sent)| sent.elapsed() > UNANSWERED);
+        if unanswered || self.keep_alive_unexpected.load(Ordering::Relaxed) > 0 {
+            flags.push(("inhuman keep alive", weights.keep_alive));
+        }
+
+        flags
+    }
+
+    pub fn summary(&self) -> String {
+        let Some(score) = *self.score.lock().unwrap() else {
+            return "not scored".to_string();
+        };
+
+        let brand = self.brand.lock().unwrap().clone().unwrap_or("none".to_string());
+        let flags = self.flags().iter().map(|(flag, _)| *flag).collect::<Vec<_>>();
+
+        match flags.is_empty() {
+            true => format!("score {score}, brand {brand}"),
+            false => format!("score {score} ({}), brand {brand}", flags.join(", ")),
+        }
+    }
+}
+
+/// Scores the session at the end of the window and acts on it, `readable`
+/// telling whether the pipe could follow the session. Never returns, so it can
+/// sit next to the pipes.
+pub async fn judge(address: SocketAddr, fingerprint: &Fingerprint, readable: impl Fn() -> bool) {
+    let config = VIGILANT_CONFIG.load();
+    let settings = &config.guardian.fingerprint;
+
+    if settings.active {
+        tokio::time::sleep(Duration::from_secs(settings.window)).await;
+    }
+
+    if !settings.active || !readable() {
+        return std::future::pending().await;
+    }
+
+    let flags = fingerprint.flags();
+    let score = flags.iter().map(|(_, weight)| weight).sum::<u32>();
+    *fingerprint.score.lock().unwrap() = Some(score);
+
+    if score < settings.threshold {
+        return std::future::pending().await;
+    }
+
+    let reason = format!("Scored {} as a bot ({})", score, flags.iter().map(|(flag, _)| *flag).collect::<Vec<_>>().join(", "));
+    let ip = guardian::ip_key(address.ip());
+    let lang = VIGILANT_LANG.load();
+
+    match settings.action {
+        FingerprintAction::Log => warn!("{}", coloriser!("[/c(dark_blue){}c(reset)] {}", address, reason)),
+        FingerprintAction::Kick => {
+            info!("{}", coloriser!("[/c(dark_blue){}c(reset)] Kicked because: {}", address, reason));
+            session::kick(|v| v.address == address, &lang.fingerprint_kick).await;
+        }
+        FingerprintAction::Ban => {
+            info!("{}", coloriser!("[/c(dark_blue){}c(reset)] Banned because: {}", address, reason));
+            guardian::ban(&ip).await;
+        }
+        FingerprintAction::Captcha => {
+            info!("{}", coloriser!("[/c(dark_blue){}c(reset)] Kicked to solve a captcha because: {}", address, reason));
+            captcha::flag(&ip);
+            session::kick(|v| v.address == address, &lang.fingerprint_captcha_kick).await;
+        }
+    }
+
+    if !matches!(settings.action, FingerprintAction::Log) {
+        events::emit(EventKind::Rejection { reason: RejectReason::Fingerprint, message: reason }, address);
+    }
+
+    std::future::pending().await
+}
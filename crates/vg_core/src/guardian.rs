@@ -1,25 +1,198 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::warn;
+use once_cell::sync::Lazy;
+
+use crate::events::{self, EventKind};
+use crate::file::config_file::RateLimiter;
 use crate::file::*;
+use crate::macros::coloriser;
+use crate::session;
+
+pub static ATTACK_MODE: AtomicBool = AtomicBool::new(false);
+static ATTACK_MODE_FORCED: AtomicBool = AtomicBool::new(false);
+static CONNECTION_RATE: AtomicUsize = AtomicUsize::new(0);
+
+/// The address itself, IPv4-mapped IPv6 addresses being their IPv4 address
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
 
-pub async fn ip_blacklisted(ip: String) -> bool {
-    if ip == "127.0.0.1" {
-        return false;
+/// What everything kept per IP is keyed on. IPv6 addresses are cut to their
+/// first `proxy.ipv6_prefix` bits, so a whole block counts as one IP.
+pub fn ip_key(ip: IpAddr) -> String {
+    key(ip, VIGILANT_CONFIG.load().proxy.ipv6_prefix)
+}
+
+fn key(ip: IpAddr, prefix: u8) -> String {
+    let prefix = prefix.min(128) as u32;
+
+    match canonical_ip(ip) {
+        IpAddr::V6(v6) if prefix < 128 => format!("{}/{}", Ipv6Addr::from(u128::from(v6) & u128::MAX.checked_shl(128 - prefix).unwrap_or(0)), prefix),
+        ip => ip.to_string(),
     }
-    if unsafe { IP_BLACKLIST_DB.has(&ip) } {
-        return true;
+}
+
+/// The key of an IP, or of an IPv6 block like `2001:db8::/64`, given by an
+/// operator or read from a database. A block is keyed like its first address,
+/// so it follows `proxy.ipv6_prefix` whatever prefix it was written with.
+pub fn normalize(ip: &str) -> Option<String> {
+    normalize_with(ip, VIGILANT_CONFIG.load().proxy.ipv6_prefix)
+}
+
+fn normalize_with(ip: &str, prefix: u8) -> Option<String> {
+    match ip.split_once('/') {
+        Some((addr, bits)) if bits.parse::<u8>().is_ok_and(|v| v <= 128) => addr.parse::<Ipv6Addr>().ok().map(|v| key(v.into(), prefix)),
+        Some(_) => None,
+        None => ip.parse().ok().map(|v| key(v, prefix)),
     }
-    if unsafe { IP_WHITELIST_DB.has(&ip) } {
-        return false;
+}
+
+/// Bans that lift on their own, only kept in memory
+static TEMP_BANS: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn banned(ip: &str) -> bool {
+    if let Some(until) = TEMP_BANS.lock().unwrap().get(ip) {
+        if *until > Instant::now() {
+            return true;
+        }
     }
 
-    let resp = reqwest::get(format!("https://proxycheck.io/v2/{ip}?vpn=2&asn=0&risk=1")).await.unwrap().text().await.unwrap();
+    IP_BANLIST_DB.lock().unwrap().has(ip)
+}
+
+/// Login attempts per IP, with when their window ends
+static LOGIN_ATTEMPTS: Lazy<Mutex<HashMap<String, (Instant, usize)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Counts a login attempt from `ip`, whether it makes more than `limiter`
+/// allows within its window
+pub fn rate_limited(ip: &str, limiter: &RateLimiter) -> bool {
+    let now = Instant::now();
+    let mut attempts = LOGIN_ATTEMPTS.lock().unwrap();
+    attempts.retain(|_, (until, _)| *until > now);
+
+    let (_, count) = attempts.entry(ip.to_string()).or_insert((now + Duration::from_secs(limiter.window), 0));
+    *count += 1;
+    *count > limiter.logins
+}
+
+/// Bans an IP for `duration` without kicking anyone, for the caller to do
+pub fn temp_ban(ip: &str, duration: Duration) {
+    let mut bans = TEMP_BANS.lock().unwrap();
+    bans.retain(|_, until| *until > Instant::now());
+    bans.insert(ip.to_string(), Instant::now() + duration);
+}
+
+/// Bans an IP and kicks everyone connected from it, returning how many were
+pub async fn ban(ip: &str) -> usize {
+    let key = normalize(ip).unwrap_or_else(|| ip.to_string());
+    if let Err(err) = IP_BANLIST_DB.lock().unwrap().push(key.clone()) {
+        warn!("{}", coloriser!("Failed to save the ban list: {}", err.to_string()));
+    }
+    session::kick(|v| ip_key(v.address.ip()) == key, &VIGILANT_LANG.load().player_banned_kick).await
+}
+
+pub fn unban(ip: &str) -> std::io::Result<bool> {
+    IP_BANLIST_DB.lock().unwrap().remove(normalize(ip).unwrap_or_else(|| ip.to_string()))
+}
+
+/// Holds attack mode on regardless of the connection rate until it is
+/// released, at which point the watcher takes over again
+pub fn force_attack_mode(active: bool) {
+    ATTACK_MODE_FORCED.store(active, Ordering::Relaxed);
+    ATTACK_MODE.store(active, Ordering::Relaxed);
+    warn!("{}", coloriser!("{} by an operator", if active { "c(on_red) ATTACK MODE ON c(reset)" } else { "c(on_green) ATTACK MODE OFF c(reset)" }));
+    events::emit_global(EventKind::AttackMode { active, rate: 0 });
+}
+
+/// Counts an accepted connection towards the attack mode threshold
+pub fn track_connection() {
+    CONNECTION_RATE.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Switches attack mode on when more than `guardian.attack_mode.threshold`
+/// connections are accepted in a second, and back off once it has been calm
+/// for `guardian.attack_mode.duration` seconds
+pub async fn attack_mode_watcher() {
+    let mut calm = 0;
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let rate = CONNECTION_RATE.swap(0, Ordering::Relaxed);
+        let config = VIGILANT_CONFIG.load();
+
+        if ATTACK_MODE_FORCED.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        if !config.guardian.attack_mode.active {
+            ATTACK_MODE.store(false, Ordering::Relaxed);
+            continue;
+        }
+
+        if rate >= config.guardian.attack_mode.threshold {
+            calm = 0;
+            if !ATTACK_MODE.swap(true, Ordering::Relaxed) {
+                warn!("{}", coloriser!("c(on_red) ATTACK MODE ON c(reset) {} connections in the last second", rate));
+                events::emit_global(EventKind::AttackMode { active: true, rate });
+            }
+        } else if ATTACK_MODE.load(Ordering::Relaxed) {
+            calm += 1;
+            if calm >= config.guardian.attack_mode.duration {
+                ATTACK_MODE.store(false, Ordering::Relaxed);
+                warn!("{}", coloriser!("c(on_green) ATTACK MODE OFF c(reset) calm for {} seconds", calm));
+                events::emit_global(EventKind::AttackMode { active: false, rate });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_ipv6_by_prefix() {
+        assert_eq!(key("2001:db8:1:2:3:4:5:6".parse().unwrap(), 64), "2001:db8:1:2::/64");
+        assert_eq!(key("2001:db8:1:2:3:4:5:6".parse().unwrap(), 48), "2001:db8:1::/48");
+        assert_eq!(key("2001:db8:1:2:3:4:5:6".parse().unwrap(), 128), "2001:db8:1:2:3:4:5:6");
+        assert_eq!(key("2001:db8:1:2:3:4:5:6".parse().unwrap(), 200), "2001:db8:1:2:3:4:5:6");
+        assert_eq!(key("2001:db8::1".parse().unwrap(), 0), "::/0");
+    }
+
+    #[test]
+    fn keys_ipv4_as_is() {
+        assert_eq!(key("192.0.2.7".parse().unwrap(), 64), "192.0.2.7");
+        assert_eq!(key("::ffff:192.0.2.7".parse().unwrap(), 64), "192.0.2.7");
+    }
+
+    #[test]
+    fn normalizes_operator_input() {
+        assert_eq!(normalize_with("192.0.2.7", 64).as_deref(), Some("192.0.2.7"));
+        assert_eq!(normalize_with("2001:db8::5", 64).as_deref(), Some("2001:db8::/64"));
+        assert_eq!(normalize_with("2001:db8::/64", 64).as_deref(), Some("2001:db8::/64"));
+        assert_eq!(normalize_with("2001:db8:0:0:ffff::/80", 64).as_deref(), Some("2001:db8::/64"));
+        assert_eq!(normalize_with("2001:db8::/64", 128).as_deref(), Some("2001:db8::"));
+        assert_eq!(normalize_with("2001:db8::/129", 64), None);
+        assert_eq!(normalize_with("192.0.2.0/24", 64), None);
+        assert_eq!(normalize_with("notch", 64), None);
+    }
 
-    let risk: u8 = resp.split("\"risk\": ").collect::<Vec<&str>>()[1].split("\n").into_iter().collect::<Vec<&str>>()[0].parse().unwrap_or(0);
+    #[test]
+    fn rate_limits_within_the_window() {
+        let limiter = RateLimiter { active: true, logins: 2, window: 60 };
 
-    if risk >= 50 {
-        unsafe { IP_BLACKLIST_DB.push(ip) }
-        return true;
-    } else {
-        unsafe { IP_WHITELIST_DB.push(ip) }
-        return false;
+        assert!(!rate_limited("192.0.2.20", &limiter));
+        assert!(!rate_limited("192.0.2.20", &limiter));
+        assert!(rate_limited("192.0.2.20", &limiter));
+        assert!(!rate_limited("192.0.2.21", &limiter));
     }
 }
@@ -1,10 +1,11 @@
 use std::borrow::Cow;
 
+use std::net::SocketAddr;
 use std::sync::atomic::Ordering;
-use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use log::info;
+use serde_json::Value;
 
 use tokio::net::tcp::OwnedReadHalf;
 
@@ -12,23 +13,41 @@ use valence_protocol::bytes::BytesMut;
 use valence_protocol::packet::s2c::login::LoginDisconnectS2c;
 use valence_protocol::text::Text;
 
+use crate::events::{self, EventKind, RejectReason};
+use crate::file::config_file::{GuardianProfile, ListenerConfig};
 use crate::file::{VIGILANT_CONFIG, VIGILANT_LANG};
-use crate::guardian::ip_blacklisted;
+use crate::guardian::{banned, canonical_ip, ip_key, rate_limited, ATTACK_MODE};
+use crate::limits;
+use crate::listener;
 use crate::macros::coloriser;
 use crate::packet::{c2s, s2c};
-use crate::{make_bytes, CONNECTIONS, IP_CACHE, RUNTIME, SERVER_ALIVE};
+use crate::plugin::{self, LoginContext, PluginVerdict};
+use crate::profile;
+use crate::script::{self, ScriptContext, ScriptVerdict};
+use crate::session::HandshakeInfo;
+use crate::shutdown::DRAINING;
+use crate::status;
+use crate::vpn;
+use crate::{make_bytes, CONNECTIONS, IP_CACHE, PLAYERS, SESSIONS};
 
 use super::interceptor::InterceptResult;
 
+/// The player's address, which isn't the one the connection came from behind
+/// a load balancer speaking the PROXY protocol
+fn address(reader: &OwnedReadHalf) -> SocketAddr {
+    listener::client(reader.peer_addr().unwrap())
+}
+
 macro_rules! log {
     ($msg:expr,$reader:expr) => {
-        info!("{}", coloriser!("[/c(dark_blue){}c(reset)] {}", $reader.peer_addr().unwrap(), $msg));
+        info!("{}", coloriser!("[/c(dark_blue){}c(reset)] {}", address($reader), $msg));
     };
 }
 
 macro_rules! reject {
-    ($reason:expr,$kick_reason:expr,$reader:expr) => {
+    ($code:expr,$reason:expr,$kick_reason:expr,$reader:expr) => {
         log!(format!("Rejected because: {}", $kick_reason), $reader);
+        events::emit(EventKind::Rejection { reason: $code, message: $kick_reason.to_string() }, address($reader));
         return Some(make_bytes!(LoginDisconnectS2c { reason: Cow::Owned(Text::from($reason)) }))
     };
 }
@@ -37,19 +56,54 @@ pub struct C2S;
 
 impl C2S {
     pub async fn handshake(mut packet: c2s::Handshake, reader: &OwnedReadHalf) -> (InterceptResult, c2s::Handshake) {
-        ip_forward(&mut packet, reader);
+        let info = HandshakeInfo::from(&packet);
+        let listener = SESSIONS.lock().await.get_mut(&address(reader).to_string()).map(|session| {
+            session.handshake = Some(info.clone());
+            session.profile = profile::select(&session.listener, &info.server_address);
+            session.listener.clone()
+        });
+
+        events::emit(EventKind::Handshake { server_address: info.server_address, server_port: info.server_port, next_state: info.next_state }, address(reader));
+
+        if let Err(err) = limits::hostname(address(reader), &packet.server_address) {
+            log!(err, reader);
+            return (InterceptResult::IGNORE, packet);
+        }
+
+        if let Some(listener) = listener {
+            ip_forward(&mut packet, reader, &listener);
+        }
 
         (InterceptResult::PASSTHROUGH, packet)
     }
 
     pub async fn query_request(packet: c2s::QueryRequest, reader: &OwnedReadHalf) -> (InterceptResult, c2s::QueryRequest) {
-        if !SERVER_ALIVE.load(Ordering::Relaxed) {
-            let motd = s2c::QueryResponse { json: format!("{{\n\"version\":{{\n\"name\":\"{}\",\n\"protocol\":999\n}},\n\"players\":{{\n\"max\":0,\n\"online\":0,\n\"sample\":[]\n}},\n\"description\":{{\n\"text\":\"{}\"\n}},\n\"favicon\":\"data:image/png;base64,\",\n\"enforcesSecureChat\":true\n}}", VIGILANT_LANG.server_version_name, VIGILANT_LANG.server_offline_motd) };
+        if DRAINING.load(Ordering::Relaxed) {
+            return (InterceptResult::RETURN(Some(make_bytes!(local_motd(&VIGILANT_LANG.load().server_restarting_motd)))), packet);
+        }
 
-            return (InterceptResult::RETURN(Some(make_bytes!(motd))), packet);
+        // Only the status of `[server]` is cached
+        let (default_server, profile) = SESSIONS.lock().await.get(&address(reader).to_string()).map_or((true, String::new()), |v| (listener::default_server(&v.listener), v.profile.clone()));
+
+        let profile = profile::resolve(&profile);
+        ip_cache(reader, &profile).await;
+        if profile.vpn_filter.active {
+            vpn::prewarm(address(reader).ip());
         }
+        events::emit(EventKind::StatusPing, address(reader));
+
+        if VIGILANT_CONFIG.load().proxy.status_cache.active && default_server {
+            let response = match status::cached() {
+                Some(json) => {
+                    let mut response = s2c::QueryResponse { json };
+                    query_response(&mut response);
+                    response
+                }
+                None => local_motd(&VIGILANT_LANG.load().server_offline_motd),
+            };
 
-        ip_cache(reader);
+            return (InterceptResult::RETURN(Some(make_bytes!(response))), packet);
+        }
 
         (InterceptResult::PASSTHROUGH, packet)
     }
@@ -58,25 +112,68 @@ impl C2S {
         (InterceptResult::PASSTHROUGH, packet)
     }
 
-    pub async fn login_hello(packet: c2s::LoginHello, reader: &OwnedReadHalf) -> (InterceptResult, c2s::LoginHello) {
-        if !SERVER_ALIVE.load(Ordering::Relaxed) {
-            let reason = LoginDisconnectS2c { reason: Cow::Owned(Text::from(VIGILANT_LANG.server_offline_kick.clone())) };
+    /// Answers a ping that came after a status the proxy answered itself
+    pub async fn local_query_ping(packet: c2s::QueryPing, _reader: &OwnedReadHalf) -> (InterceptResult, c2s::QueryPing) {
+        (InterceptResult::RETURN(Some(make_bytes!(s2c::QueryPong { payload: packet.payload }))), packet)
+    }
+
+    pub async fn login_hello(mut packet: c2s::LoginHello, reader: &OwnedReadHalf) -> (InterceptResult, c2s::LoginHello) {
+        if let Err(err) = limits::username(address(reader), &packet.username) {
+            log!(err, reader);
+            return (InterceptResult::IGNORE, packet);
+        }
+
+        let addr = address(reader).to_string();
+        let profile = SESSIONS.lock().await.get_mut(&addr).map(|session| {
+            session.username = Some(packet.username.clone());
+            session.profile.clone()
+        });
+        let profile = profile::resolve(&profile.unwrap_or_default());
+        events::emit(EventKind::LoginAttempt, address(reader));
+
+        if let Some(bytes) = ban_filter(reader).await {
+            return (InterceptResult::RETURN(Some(bytes)), packet);
+        }
+
+        if let Some(bytes) = drain_filter(reader).await {
+            return (InterceptResult::RETURN(Some(bytes)), packet);
+        }
+
+        if let Some(bytes) = rate_filter(reader, &profile).await {
+            return (InterceptResult::RETURN(Some(bytes)), packet);
+        }
+
+        if let Some(bytes) = whitelist_filter(&packet, reader, &profile).await {
+            return (InterceptResult::RETURN(Some(bytes)), packet);
+        }
+
+        if let Some(bytes) = ping_filter(reader, &profile).await {
+            return (InterceptResult::RETURN(Some(bytes)), packet);
+        }
 
-            return (InterceptResult::RETURN(Some(make_bytes!(reason))), packet);
+        if let Some(bytes) = concurrency_filter(reader, &profile).await {
+            return (InterceptResult::RETURN(Some(bytes)), packet);
         }
 
-        if let Some(bytes) = ping_filter(reader).await {
+        if let Some(bytes) = vpn_filter(reader, &profile).await {
             return (InterceptResult::RETURN(Some(bytes)), packet);
         }
 
-        if let Some(bytes) = concurrency_filter(reader).await {
+        if let Some(bytes) = plugin_filter(&mut packet, reader).await {
             return (InterceptResult::RETURN(Some(bytes)), packet);
         }
 
-        if let Some(bytes) = vpn_filter(reader).await {
+        if let Some(bytes) = script_filter(&packet, reader).await {
             return (InterceptResult::RETURN(Some(bytes)), packet);
         }
 
+        // A plugin may have rewritten it
+        if let Some(session) = SESSIONS.lock().await.get_mut(&addr) {
+            session.username = Some(packet.username.clone());
+        }
+        PLAYERS.lock().await.insert(addr, packet.username.clone());
+        events::emit(EventKind::LoginAllowed, address(reader));
+
         (InterceptResult::PASSTHROUGH, packet)
     }
 }
@@ -93,85 +190,197 @@ impl S2C {
     }
 }
 
-pub fn ip_cache(reader: &OwnedReadHalf) {
-    let ip = reader.peer_addr().unwrap().ip().to_string();
+/// Status response for when the server couldn't be reached
+pub fn offline_status() -> BytesMut {
+    make_bytes!(local_motd(&VIGILANT_LANG.load().server_offline_motd))
+}
+
+/// Login disconnect for when the server couldn't be reached after the
+/// client got through every filter
+pub async fn offline_login(address: SocketAddr) -> BytesMut {
+    info!("{}", coloriser!("[/c(dark_blue){}c(reset)] Rejected because: Server is offline", address));
+    events::emit(EventKind::Rejection { reason: RejectReason::Offline, message: "Server is offline".to_string() }, address);
+
+    make_bytes!(LoginDisconnectS2c { reason: Cow::Owned(Text::from(VIGILANT_LANG.load().server_offline_kick.clone())) })
+}
+
+/// Login disconnect for when the server is full, telling the player its
+/// place in the queue
+pub async fn queue_login(address: SocketAddr, position: usize) -> BytesMut {
+    info!("{}", coloriser!("[/c(dark_blue){}c(reset)] Queued at #{}, kicked until it reconnects", address, position));
+    events::emit(EventKind::Queued { position }, address);
+
+    let reason = VIGILANT_LANG.load().queue_kick.replace("{position}", &position.to_string()).replace("{seconds}", &VIGILANT_CONFIG.load().queue.reconnect.to_string());
+    make_bytes!(LoginDisconnectS2c { reason: Cow::Owned(Text::from(reason)) })
+}
+
+/// A status response made by the proxy itself, for when the server can't or
+/// shouldn't be asked
+fn local_motd(description: &str) -> s2c::QueryResponse {
+    s2c::QueryResponse { json: format!("{{\n\"version\":{{\n\"name\":\"{}\",\n\"protocol\":999\n}},\n\"players\":{{\n\"max\":0,\n\"online\":0,\n\"sample\":[]\n}},\n\"description\":{{\n\"text\":\"{}\"\n}},\n\"favicon\":\"data:image/png;base64,\",\n\"enforcesSecureChat\":true\n}}", VIGILANT_LANG.load().server_version_name, description) }
+}
+
+/// Remembers that the IP pinged for the profile's `reset_interval`, whether
+/// or not its ping protection is on, as that is only applied at the login
+pub async fn ip_cache(reader: &OwnedReadHalf, profile: &GuardianProfile) {
+    let ip = ip_key(address(reader).ip());
+    let now = Instant::now();
     log!("Saving IP", &reader);
-    thread::spawn(move || {
-        if VIGILANT_CONFIG.guardian.ping_protection.active {
-            RUNTIME.spawn(async move {
-                let timestamp = chrono::Utc::now().timestamp();
 
-                if let Some(_) = { IP_CACHE.lock().await.values().find(|&v| v == &ip) } {
-                    return;
-                }
+    let mut cache = IP_CACHE.lock().await;
+    cache.retain(|_, expires| *expires > now);
+    cache.entry(ip).or_insert(now + Duration::from_secs(profile.ping_protection.reset_interval));
+}
+
+/// Whether the IP key pinged within its `reset_interval`
+pub async fn pinged(ip: &str) -> bool {
+    IP_CACHE.lock().await.get(ip).is_some_and(|expires| *expires > Instant::now())
+}
+
+pub fn ip_forward(packet: &mut c2s::Handshake, reader: &OwnedReadHalf, listener: &ListenerConfig) {
+    if listener::ip_forward(listener) {
+        packet.server_address = format!("{addr}|{player_addr}", addr = packet.server_address, player_addr = canonical_ip(address(reader).ip()));
+    }
+}
+
+/// Puts `server_motd` and `server_version_name` in place of the server's own
+/// in the cached status, unless `proxy.forwarder.motd_forward`. A status the
+/// server answers itself is passed through as it is.
+pub fn query_response(packet: &mut s2c::QueryResponse) {
+    if VIGILANT_CONFIG.load().proxy.forwarder.motd_forward {
+        return;
+    }
 
-                drop(IP_CACHE.lock().await.insert(timestamp, ip));
+    let Ok(mut json) = serde_json::from_str::<Value>(&packet.json) else {
+        return;
+    };
 
-                thread::sleep(Duration::from_secs(VIGILANT_CONFIG.guardian.ping_protection.reset_interval));
+    let lang = VIGILANT_LANG.load();
 
-                IP_CACHE.lock().await.remove(&timestamp);
-            });
-        }
-    });
+    json["description"] = serde_json::json!({ "text": lang.server_motd });
+    if let Some(version) = json.get_mut("version").and_then(Value::as_object_mut) {
+        version.insert("name".to_string(), Value::from(lang.server_version_name.clone()));
+    }
+
+    packet.json = json.to_string();
 }
 
-pub fn ip_forward(packet: &mut c2s::Handshake, reader: &OwnedReadHalf) {
-    if VIGILANT_CONFIG.proxy.forwarder.ip_forward {
-        packet.server_address = format!("{addr}|{player_addr}", addr = packet.server_address, player_addr = reader.peer_addr().unwrap().ip().to_string());
+pub async fn ban_filter(reader: &OwnedReadHalf) -> Option<BytesMut> {
+    if banned(&ip_key(address(reader).ip())) {
+        reject!(RejectReason::Ban, VIGILANT_LANG.load().player_banned_kick.clone(), "Banned", reader);
     }
+
+    None
 }
 
-// pub fn query_response(packet: &mut c2s::QueryResponse) {
-//     if !VIGILANT_CONFIG.proxy.forwarder.motd_forward {
-//         let json = serde_json::from_str::<Value>(&packet.json).unwrap();
-//         let source = packet.json.to_string();
+pub async fn drain_filter(reader: &OwnedReadHalf) -> Option<BytesMut> {
+    if DRAINING.load(Ordering::Relaxed) {
+        reject!(RejectReason::Draining, VIGILANT_LANG.load().server_restarting_kick.clone(), "Draining", reader);
+    }
 
-//         let description = Text::from(VIGILANT_LANG.server_motd.clone());
-//         let description_from = serde_json::to_string(&json["description"]).unwrap();
-//         let description_to = serde_json::to_string(&description).unwrap();
+    None
+}
 
-//         let version_name = &VIGILANT_LANG.server_version_name;
-//         let version_from = serde_json::to_string(&json["version"]["name"]).unwrap();
-//         let version_to = serde_json::to_string(&version_name).unwrap();
+pub async fn whitelist_filter(packet: &c2s::LoginHello, reader: &OwnedReadHalf, profile: &GuardianProfile) -> Option<BytesMut> {
+    let whitelist = &profile.whitelist;
 
-//         let source = source.replace(&description_from, &description_to).replace(&version_from, &version_to);
+    if whitelist.active && !whitelist.players.iter().any(|v| v.eq_ignore_ascii_case(&packet.username)) {
+        reject!(RejectReason::Whitelist, VIGILANT_LANG.load().player_not_whitelisted_kick.clone(), "Not whitelisted", reader);
+    }
 
-//         packet.json = source;
-//     }
-// }
+    None
+}
 
-pub async fn vpn_filter(reader: &OwnedReadHalf) -> Option<BytesMut> {
-    let ip = reader.peer_addr().unwrap().ip().to_string();
+pub async fn vpn_filter(reader: &OwnedReadHalf, profile: &GuardianProfile) -> Option<BytesMut> {
+    let ip = address(reader).ip();
 
-    if VIGILANT_CONFIG.guardian.vpn_filter.active {
-        if ip_blacklisted(ip).await {
-            reject!(VIGILANT_LANG.player_ip_blacklisted_kick.clone(), "Using VPN/Proxy", reader);
-        }
+    if profile.vpn_filter.active && vpn::blacklisted(ip).await {
+        reject!(RejectReason::Vpn, VIGILANT_LANG.load().player_ip_blacklisted_kick.clone(), "Using VPN/Proxy", reader);
     }
 
     None
 }
 
-pub async fn concurrency_filter(reader: &OwnedReadHalf) -> Option<BytesMut> {
-    let ip = reader.peer_addr().unwrap().ip().to_string();
+pub async fn concurrency_filter(reader: &OwnedReadHalf, profile: &GuardianProfile) -> Option<BytesMut> {
+    let ip = ip_key(address(reader).ip());
 
-    if VIGILANT_CONFIG.guardian.ip_connection_limit.active {
-        if CONNECTIONS.lock().await.get(&ip).unwrap() >= &VIGILANT_CONFIG.guardian.ip_connection_limit.limit {
-            reject!(VIGILANT_LANG.player_connection_more_kick.clone(), "IP Connection limit is exceeded", reader);
-        }
+    if profile.ip_connection_limit.active && CONNECTIONS.lock().await.get(&ip).unwrap() >= &profile.ip_connection_limit.limit {
+        reject!(RejectReason::ConnectionLimit, VIGILANT_LANG.load().player_connection_more_kick.clone(), "IP Connection limit is exceeded", reader);
+    }
+
+    None
+}
+
+pub async fn rate_filter(reader: &OwnedReadHalf, profile: &GuardianProfile) -> Option<BytesMut> {
+    let ip = ip_key(address(reader).ip());
+
+    if profile.rate_limit.active && rate_limited(&ip, &profile.rate_limit) {
+        reject!(RejectReason::RateLimit, VIGILANT_LANG.load().player_rate_limited_kick.clone(), "IP is logging in too often", reader);
+    }
+
+    None
+}
+
+pub async fn ping_filter(reader: &OwnedReadHalf, profile: &GuardianProfile) -> Option<BytesMut> {
+    let ip = ip_key(address(reader).ip());
+
+    if profile.ping_protection.active && !pinged(&ip).await {
+        reject!(RejectReason::PingNotCached, VIGILANT_LANG.load().player_ping_not_cached_kick.clone(), "Player have not pinged", reader);
     }
 
     None
 }
 
-pub async fn ping_filter(reader: &OwnedReadHalf) -> Option<BytesMut> {
-    let ip = reader.peer_addr().unwrap().ip().to_string();
+pub async fn plugin_filter(packet: &mut c2s::LoginHello, reader: &OwnedReadHalf) -> Option<BytesMut> {
+    if !VIGILANT_CONFIG.load().plugins.active {
+        return None;
+    }
+
+    let addr = address(reader);
+    let key = ip_key(addr.ip());
 
-    if VIGILANT_CONFIG.guardian.ping_protection.active {
-        if let None = IP_CACHE.lock().await.values().find(|&v| v == &ip) {
-            reject!(VIGILANT_LANG.player_ping_not_cached_kick.clone(), "Player have not pinged", reader);
+    let session = SESSIONS.lock().await.get(&addr.to_string()).cloned()?;
+    let connections = *CONNECTIONS.lock().await.get(&key).unwrap_or(&0);
+    let pinged = pinged(&key).await;
+
+    let context = LoginContext { ip: canonical_ip(addr.ip()).to_string(), session, username: packet.username.clone(), profile_id: packet.profile_id.map(|v| v.to_string()), connections, pinged };
+
+    match plugin::on_login(context).await {
+        PluginVerdict::Allow => {}
+        PluginVerdict::Kick { reason } => {
+            reject!(RejectReason::Plugin, reason.clone(), format!("Kicked by plugin ({reason})"), reader);
+        }
+        PluginVerdict::Modify { username } => {
+            if let Some(username) = username {
+                log!(format!("Username rewritten by plugin to {username}"), reader);
+                packet.username = username;
+            }
         }
     }
 
     None
 }
+
+pub async fn script_filter(packet: &c2s::LoginHello, reader: &OwnedReadHalf) -> Option<BytesMut> {
+    if !VIGILANT_CONFIG.load().scripts.active {
+        return None;
+    }
+
+    let addr = address(reader);
+    let key = ip_key(addr.ip());
+
+    let session = SESSIONS.lock().await.get(&addr.to_string()).cloned()?;
+    let (connections, total_connections) = {
+        let lock = CONNECTIONS.lock().await;
+        (*lock.get(&key).unwrap_or(&0), lock.values().sum())
+    };
+
+    let context = ScriptContext { ip: canonical_ip(addr.ip()).to_string(), session, username: packet.username.clone(), profile_id: packet.profile_id.map(|v| v.to_string()), connections, total_connections, attack_mode: ATTACK_MODE.load(Ordering::Relaxed) };
+
+    if let ScriptVerdict::Deny(reason) = tokio::task::spawn_blocking(move || script::evaluate(context)).await.unwrap() {
+        let kick = reason.unwrap_or(VIGILANT_LANG.load().player_script_kick.clone());
+        reject!(RejectReason::Script, kick.clone(), format!("Denied by script ({kick})"), reader);
+    }
+
+    None
+}
@@ -6,6 +6,9 @@ use valence_protocol::decoder::{decode_packet, PacketDecoder};
 use valence_protocol::encoder::PacketEncoder;
 use valence_protocol::Packet;
 
+use super::pipe::read_var_int;
+use crate::limits::{self, Stage, Violation};
+use crate::listener;
 use crate::packet::PacketDirection;
 
 pub enum InterceptResult {
@@ -21,6 +24,14 @@ pub struct Interceptor<'b> {
     pub encoder: PacketEncoder,
     pub decoder: PacketDecoder,
     pub frame: BytesMut,
+    /// What was read but isn't a whole frame yet
+    pub buffer: BytesMut,
+    /// What the client's frames are held to, the server's aren't
+    pub stage: Stage,
+    /// Packets let through before there was anyone to write them to
+    pub pending: BytesMut,
+    /// Whether the last packet was let through to the other side
+    pub passed_through: bool,
     pub other: Option<&'b Mutex<Interceptor<'b>>>,
 }
 
@@ -31,23 +42,52 @@ impl<'b> Interceptor<'b> {
         F: FnOnce(P, &'a OwnedReadHalf) -> Fut,
         Fut: futures::Future<Output = (InterceptResult, P)>,
     {
+        let client = matches!(self.direction, PacketDirection::C2S);
+        let address = listener::client(self.reader.as_ref().unwrap().peer_addr()?);
+
         loop {
+            let next = match read_var_int(&self.buffer) {
+                Ok(next) => next,
+                Err(err) if client => return Err(limits::violation(address, Violation::Malformed, self.stage, err)),
+                Err(err) => return Err(err),
+            };
+
+            if let Some((len, header)) = next {
+                if client {
+                    limits::frame(address, self.stage, len as usize)?;
+                }
+
+                if self.buffer.len() >= header + len as usize {
+                    let frame = self.buffer.split_to(header + len as usize);
+                    self.decoder.queue_bytes(frame);
+                }
+            }
+
             if let Some(frame) = self.decoder.try_next_packet()? {
                 self.frame = frame;
 
-                let packet: P = decode_packet(&self.frame)?;
+                let packet: P = match decode_packet(&self.frame) {
+                    Ok(packet) => packet,
+                    Err(err) if client => return Err(limits::violation(address, Violation::Malformed, self.stage, err)),
+                    Err(err) => return Err(err),
+                };
 
                 let result = intercept(packet, self.reader.as_ref().unwrap()).await;
 
                 let packet = result.1;
 
+                self.passed_through = matches!(result.0, InterceptResult::PASSTHROUGH);
+
                 match result.0 {
                     InterceptResult::PASSTHROUGH => {
                         self.encoder.append_packet(&packet)?;
 
                         let bytes = self.encoder.take();
 
-                        self.writer.as_mut().unwrap().write_all(&bytes).await?;
+                        match self.writer.as_mut() {
+                            Some(writer) => writer.write_all(&bytes).await?,
+                            None => self.pending.extend_from_slice(&bytes),
+                        }
                     }
                     InterceptResult::RETURN(bytes) => {
                         if let Some(bytes) = bytes {
@@ -70,12 +110,15 @@ impl<'b> Interceptor<'b> {
                 return Ok(packet);
             }
 
-            self.decoder.reserve(4096);
-            let mut buf = self.decoder.take_capacity();
+            if client {
+                limits::buffer(address, self.stage, self.buffer.len())?;
+            }
 
-            self.reader.as_mut().unwrap().read_buf(&mut buf).await?;
+            self.buffer.reserve(4096);
 
-            self.decoder.queue_bytes(buf);
+            if self.reader.as_mut().unwrap().read_buf(&mut self.buffer).await? == 0 {
+                anyhow::bail!("Connection closed");
+            }
         }
     }
 }
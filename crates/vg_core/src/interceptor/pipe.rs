@@ -0,0 +1,376 @@
+//! Forwarding of a session once its login went through.
+//!
+//! Bytes are only passed on in whole frames, so the proxy always knows where
+//! the next packet starts and can slip its own disconnect in between them. The
+//! login packets coming from the server are followed until the session is in
+//! play, after which frames are only looked into for the fingerprinting while
+//! its window is open. Once the server asks for encryption the stream can't be
+//! read anymore and is copied as it is.
+//!
+//! A session the server took over from the limbo is compressed on the
+//! server's side only, so its frames are converted on the way through.
+
+use std::io::Read;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use flate2::read::ZlibDecoder;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::mpsc::UnboundedReceiver;
+use valence_protocol::bytes::BytesMut;
+use valence_protocol::text::Text;
+use valence_protocol::var_int::VarInt;
+use valence_protocol::{Decode, Encode};
+
+use crate::file::VIGILANT_CONFIG;
+use crate::fingerprint::Fingerprint;
+use crate::limits::{self, Stage, Violation};
+use crate::packet::{play_disconnect_id, play_ids, PacketDirection, PlayIds};
+use crate::traffic::{self, Traffic};
+use crate::{listener, profile};
+
+const LOGIN: u8 = 0;
+const PLAY: u8 = 1;
+const OPAQUE: u8 = 2;
+
+/// The longest frame the protocol allows, the most a partial frame can make
+/// the buffer hold whether or not `guardian.limits` is on
+const MAX_FRAME: usize = 2097151;
+
+/// The largest packet the game accepts once uncompressed
+const MAX_PACKET: usize = 8388608;
+
+/// What both directions of a session know about its stream
+pub struct PipeState {
+    protocol: i32,
+    compression: AtomicBool,
+    phase: AtomicU8,
+    /// Whether the server compresses while the client doesn't
+    transcode: bool,
+    session: Arc<Traffic>,
+    ip: Arc<Traffic>,
+    fingerprint: Arc<Fingerprint>,
+    ids: Option<PlayIds>,
+    started: Instant,
+    /// Guardian profile the bandwidth caps come from
+    profile: String,
+}
+
+impl PipeState {
+    pub fn new(protocol: i32, session: Arc<Traffic>, ip: Arc<Traffic>, fingerprint: Arc<Fingerprint>, profile: String) -> Self {
+        Self { protocol, compression: AtomicBool::new(false), phase: AtomicU8::new(LOGIN), transcode: false, session, ip, fingerprint, ids: play_ids(protocol), started: Instant::now(), profile }
+    }
+
+    /// For a session that was logged in by the limbo
+    pub fn transferred(protocol: i32, compression: bool, session: Arc<Traffic>, ip: Arc<Traffic>, fingerprint: Arc<Fingerprint>, profile: String) -> Self {
+        Self { protocol, compression: AtomicBool::new(false), phase: AtomicU8::new(PLAY), transcode: compression, session, ip, fingerprint, ids: play_ids(protocol), started: Instant::now(), profile }
+    }
+
+    fn opaque(&self) -> bool {
+        self.phase.load(Ordering::Relaxed) == OPAQUE
+    }
+
+    /// Whether the fingerprinting can follow the session
+    pub fn readable(&self) -> bool {
+        !self.opaque() && self.ids.is_some()
+    }
+
+    pub fn fingerprint(&self) -> &Fingerprint {
+        &self.fingerprint
+    }
+
+    fn watching(&self) -> bool {
+        let config = VIGILANT_CONFIG.load();
+        let fingerprint = &config.guardian.fingerprint;
+        fingerprint.active && self.readable() && self.started.elapsed() < Duration::from_secs(fingerprint.window)
+    }
+
+    /// Length and count of the leading whole frames in `buf`, following the
+    /// login packets sent by the server along the way and the play packets
+    /// the fingerprinting looks at. The client's frames are held to the play
+    /// limit, `address` being the client's.
+    fn complete_frames(&self, direction: &PacketDirection, buf: &[u8], address: SocketAddr) -> anyhow::Result<(usize, usize)> {
+        let mut offset = 0;
+        let mut frames = 0;
+
+        loop {
+            let (len, header) = match (read_var_int(&buf[offset..]), direction) {
+                (Ok(Some(next)), _) => next,
+                (Ok(None), _) => break,
+                (Err(err), PacketDirection::C2S) => return Err(limits::violation(address, Violation::Malformed, Stage::Play, err)),
+                (Err(err), PacketDirection::S2C) => return Err(err),
+            };
+
+            if len < 0 || len as usize > MAX_FRAME {
+                let err = anyhow::anyhow!("frame of {len} bytes, the protocol allows {MAX_FRAME}");
+                return Err(match direction {
+                    PacketDirection::C2S => limits::violation(address, Violation::OversizedFrame, Stage::Play, err),
+                    PacketDirection::S2C => err,
+                });
+            }
+            if let PacketDirection::C2S = direction {
+                limits::frame(address, Stage::Play, len as usize)?;
+            }
+
+            let end = offset + header + len as usize;
+            if end > buf.len() {
+                break;
+            }
+
+            match (self.phase.load(Ordering::Relaxed), direction) {
+                (LOGIN, PacketDirection::S2C) => self.inspect(&buf[offset + header..end])?,
+                // A packet the fingerprinting can't make sense of is for the
+                // server to complain about
+                (PLAY, _) if self.watching() => {
+                    let _ = self.observe(direction, &buf[offset + header..end]);
+                }
+                _ => {}
+            }
+
+            offset = end;
+            frames += 1;
+
+            // Everything after an encryption request is ciphertext
+            if self.opaque() {
+                return Ok((buf.len(), frames));
+            }
+        }
+
+        Ok((offset, frames))
+    }
+
+    fn inspect(&self, frame: &[u8]) -> anyhow::Result<()> {
+        let data = uncompress(frame, self.compression.load(Ordering::Relaxed))?;
+        let mut data = data.as_slice();
+
+        match VarInt::decode(&mut data)?.0 {
+            0x01 => self.phase.store(OPAQUE, Ordering::Relaxed),
+            0x02 => self.phase.store(PLAY, Ordering::Relaxed),
+            0x03 => self.compression.store(VarInt::decode(&mut data)?.0 >= 0, Ordering::Relaxed),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn observe(&self, direction: &PacketDirection, frame: &[u8]) -> anyhow::Result<()> {
+        let compressed = match direction {
+            PacketDirection::C2S => self.compression.load(Ordering::Relaxed),
+            PacketDirection::S2C => self.compression.load(Ordering::Relaxed) || self.transcode,
+        };
+
+        self.fingerprint.observe(direction, self.ids.as_ref().unwrap(), &uncompress(frame, compressed)?)
+    }
+
+    /// Rewrites whole frames between the server's compression and the
+    /// client's lack of it. The client's are sent as uncompressed packets of a
+    /// compressed stream, which the server takes at any size.
+    fn transcode(&self, direction: &PacketDirection, frames: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(frames.len());
+        let mut offset = 0;
+
+        while let Some((len, header)) = read_var_int(&frames[offset..])? {
+            let frame = &frames[offset + header..offset + header + len as usize];
+            offset += header + len as usize;
+
+            match direction {
+                PacketDirection::C2S => {
+                    VarInt(len + 1).encode(&mut out)?;
+                    VarInt(0).encode(&mut out)?;
+                    out.extend_from_slice(frame);
+                }
+                PacketDirection::S2C => {
+                    let data = uncompress(frame, true)?;
+                    VarInt(data.len() as i32).encode(&mut out)?;
+                    out.extend_from_slice(&data);
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// A disconnect packet for wherever the session is at, `None` when the
+    /// proxy can't tell which one the client expects
+    fn disconnect(&self, reason: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let id = match self.phase.load(Ordering::Relaxed) {
+            LOGIN => 0x00,
+            PLAY => match play_disconnect_id(self.protocol) {
+                Some(id) => id,
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+
+        let mut data = Vec::new();
+        VarInt(id).encode(&mut data)?;
+        Text::from(reason.to_string()).encode(&mut data)?;
+
+        // A data length of 0 marks the packet as sent uncompressed
+        let compression = self.compression.load(Ordering::Relaxed);
+
+        let mut frame = Vec::new();
+        VarInt(data.len() as i32 + compression as i32).encode(&mut frame)?;
+        if compression {
+            VarInt(0).encode(&mut frame)?;
+        }
+        frame.extend_from_slice(&data);
+
+        Ok(Some(frame))
+    }
+}
+
+/// The packet in a frame, `compressed` when the stream is
+pub fn uncompress(frame: &[u8], compressed: bool) -> anyhow::Result<Vec<u8>> {
+    if !compressed {
+        return Ok(frame.to_vec());
+    }
+
+    let mut frame = frame;
+    let data_len = VarInt::decode(&mut frame)?.0;
+
+    if data_len == 0 {
+        return Ok(frame.to_vec());
+    }
+
+    anyhow::ensure!((0..=MAX_PACKET as i32).contains(&data_len), "Invalid uncompressed length {data_len}");
+
+    let mut data = Vec::with_capacity(data_len as usize);
+    ZlibDecoder::new(frame).take(data_len as u64).read_to_end(&mut data)?;
+
+    Ok(data)
+}
+
+/// Reads a frame length prefix, `None` if it isn't all there yet
+pub fn read_var_int(buf: &[u8]) -> anyhow::Result<Option<(i32, usize)>> {
+    let mut value = 0;
+
+    for (i, byte) in buf.iter().take(VarInt::MAX_SIZE).enumerate() {
+        value |= (*byte as i32 & 0x7F) << (i * 7);
+
+        if byte & 0x80 == 0 {
+            anyhow::ensure!(value >= 0, "Negative frame length");
+            return Ok(Some((value, i + 1)));
+        }
+    }
+
+    anyhow::ensure!(buf.len() < VarInt::MAX_SIZE, "Frame length is too big");
+
+    Ok(None)
+}
+
+/// Forwards one direction of a session until either side closes. The pipe
+/// writing to the client also takes the session's kick channel, a kick sends
+/// the reason as a disconnect between two frames and ends the session.
+///
+/// What the client sends is throttled to the bandwidth of its guardian
+/// profile, by not reading from it again until the session and its IP are
+/// back within their caps, and a client that sends nothing for
+/// `guardian.timeouts.idle` is disconnected.
+pub async fn pipe(direction: PacketDirection, mut reader: OwnedReadHalf, mut writer: OwnedWriteHalf, state: &PipeState, mut kick: Option<&mut UnboundedReceiver<String>>) -> anyhow::Result<()> {
+    let mut buf = BytesMut::with_capacity(8192);
+    let address = listener::client(match direction {
+        PacketDirection::C2S => reader.peer_addr()?,
+        PacketDirection::S2C => writer.peer_addr()?,
+    });
+
+    loop {
+        let kicked = async {
+            match kick.as_mut() {
+                Some(kick) => kick.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let idle = match direction {
+            PacketDirection::C2S => VIGILANT_CONFIG.load().guardian.timeouts.idle,
+            PacketDirection::S2C => 0,
+        };
+
+        let read = async {
+            match idle {
+                0 => Ok(reader.read_buf(&mut buf).await?),
+                idle => tokio::time::timeout(Duration::from_secs(idle), reader.read_buf(&mut buf)).await.map_err(|_| anyhow::anyhow!("Idle for more than {idle} seconds"))?.map_err(anyhow::Error::from),
+            }
+        };
+
+        let bytes_read = tokio::select! {
+            bytes_read = read => bytes_read?,
+            Some(reason) = kicked => {
+                if let Some(disconnect) = state.disconnect(&reason)? {
+                    writer.write_all(&disconnect).await?;
+                }
+                return Ok(());
+            }
+        };
+
+        if bytes_read == 0 {
+            return Ok(());
+        }
+
+        let (complete, frames) = if state.opaque() { (buf.len(), 0) } else { state.complete_frames(&direction, &buf, address)? };
+
+        if complete > 0 {
+            let complete = buf.split_to(complete);
+
+            if state.transcode {
+                writer.write_all(&state.transcode(&direction, &complete)?).await?;
+            } else {
+                writer.write_all(&complete).await?;
+            }
+        }
+
+        traffic::record(&direction, &state.session, &state.ip, bytes_read, frames);
+
+        if let PacketDirection::C2S = direction {
+            let bandwidth = profile::bandwidth(&state.profile);
+
+            if bandwidth.active {
+                let wait = state.session.throttle(bytes_read, bandwidth.connection_limit * 1000).max(state.ip.throttle(bytes_read, bandwidth.ip_limit * 1000));
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> PipeState {
+        PipeState::new(763, Arc::default(), Arc::default(), Arc::default(), String::new())
+    }
+
+    fn frames(buf: &[u8]) -> anyhow::Result<(usize, usize)> {
+        state().complete_frames(&PacketDirection::S2C, buf, "192.0.2.7:51234".parse().unwrap())
+    }
+
+    #[test]
+    fn counts_whole_frames() {
+        assert_eq!(frames(&[]).unwrap(), (0, 0));
+        assert_eq!(frames(&[0x03, 0x10, 0x00, 0x00, 0x02, 0x10]).unwrap(), (4, 1));
+        assert_eq!(frames(&[0x01, 0x10, 0x01, 0x10]).unwrap(), (4, 2));
+        assert_eq!(frames(&[0x80]).unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn refuses_frames_past_the_protocol() {
+        assert!(frames(&[0xFF, 0xFF, 0x7F]).is_ok());
+        assert!(frames(&[0x80, 0x80, 0x80, 0x01]).is_err());
+        assert!(frames(&[0xFF, 0xFF, 0xFF, 0xFF, 0x0F]).is_err());
+        assert!(frames(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]).is_err());
+    }
+
+    #[test]
+    fn refuses_impossible_uncompressed_lengths() {
+        // Data lengths of -1 and 8388609, with nothing behind them
+        assert!(uncompress(&[0xFF, 0xFF, 0xFF, 0xFF, 0x0F], true).is_err());
+        assert!(uncompress(&[0x81, 0x80, 0x80, 0x04], true).is_err());
+        assert_eq!(uncompress(&[0x00, 0x10, 0x01], true).unwrap(), vec![0x10, 0x01]);
+    }
+}
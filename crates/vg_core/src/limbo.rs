@@ -0,0 +1,416 @@
+//! Waiting room for when the server is down or full. With `limbo.active`, a
+//! player that got through the gate while the server couldn't be reached is
+//! logged in by the proxy itself, into an empty world showing `limbo_title`
+//! and `limbo_actionbar`, and so is a player waiting in the join queue with
+//! `queue.limbo`, showing its position instead. Once the health check sees
+//! the server again and the player has a place on it, the proxy logs into it
+//! in the player's place and hands the session over, the server's own join
+//! packet moving the player into the real world.
+//!
+//! Only offline mode servers can take such a player, since the proxy has
+//! nobody to answer an encryption request, and only the protocols between
+//! `limbo.min_protocol` and `limbo.max_protocol` that the proxy knows how to
+//! speak (762 and 763, 1.19.4 - 1.20.1) are held.
+
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use log::{info, warn};
+use md5::{Digest, Md5};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::UnboundedReceiver;
+use valence_nbt::{compound, Compound, List};
+use valence_protocol::bytes::BytesMut;
+use valence_protocol::text::Text;
+use valence_protocol::uuid::Uuid;
+use valence_protocol::var_int::VarInt;
+use valence_protocol::{Decode, Encode};
+
+use crate::file::config_file::ListenerConfig;
+use crate::file::{VIGILANT_CONFIG, VIGILANT_LANG};
+use crate::interceptor::pipe::{read_var_int, uncompress};
+use crate::limits::{self, Stage};
+use crate::macros::coloriser;
+use crate::packet::s2c::{self, Trailing};
+use crate::queue::{self, Slot};
+use crate::{deadline, listener, make_bytes, SERVER_ALIVE};
+
+/// Protocols whose play packets are in [`s2c`]
+const SUPPORTED: [i32; 2] = [762, 763];
+
+/// How long to leave the server alone after it turned a player down
+const RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// A limbo player the server took over, with the server's login done
+pub struct Transferred {
+    pub client_reader: OwnedReadHalf,
+    pub client_writer: OwnedWriteHalf,
+    pub server: TcpStream,
+    /// Whether the server compresses its packets, which the client doesn't
+    /// expect as it never got a compression request
+    pub compression: bool,
+    pub slot: Slot,
+}
+
+enum Attempt {
+    Joined(TcpStream, bool),
+    /// The server's login disconnect reason
+    Refused(Text),
+    /// The server is in online mode, asking again won't change that
+    Encrypted,
+}
+
+/// Whether the limbo can hold a client of `protocol`
+pub fn speaks(protocol: i32) -> bool {
+    let limbo = &VIGILANT_CONFIG.load().limbo;
+    SUPPORTED.contains(&protocol) && (limbo.min_protocol..=limbo.max_protocol).contains(&protocol)
+}
+
+/// Whether a client of `protocol` is held while the server is down
+pub fn accepts(protocol: i32) -> bool {
+    VIGILANT_CONFIG.load().limbo.active && speaks(protocol)
+}
+
+/// The UUID an offline mode server gives `username`, a version 3 UUID of
+/// `OfflinePlayer:<username>`
+pub fn offline_uuid(username: &str) -> Uuid {
+    let mut hash: [u8; 16] = Md5::digest(format!("OfflinePlayer:{username}")).into();
+    hash[6] = hash[6] & 0x0F | 0x30;
+    hash[8] = hash[8] & 0x3F | 0x80;
+
+    Uuid::from_bytes(hash)
+}
+
+/// Every damage type the client looks up on joining a world, and the
+/// translation key of its death message
+const DAMAGE_TYPES: [(&str, &str); 42] = [
+    ("minecraft:in_fire", "inFire"),
+    ("minecraft:lightning_bolt", "lightningBolt"),
+    ("minecraft:on_fire", "onFire"),
+    ("minecraft:lava", "lava"),
+    ("minecraft:hot_floor", "hotFloor"),
+    ("minecraft:in_wall", "inWall"),
+    ("minecraft:cramming", "cramming"),
+    ("minecraft:drown", "drown"),
+    ("minecraft:starve", "starve"),
+    ("minecraft:cactus", "cactus"),
+    ("minecraft:fall", "fall"),
+    ("minecraft:fly_into_wall", "flyIntoWall"),
+    ("minecraft:out_of_world", "outOfWorld"),
+    ("minecraft:generic", "generic"),
+    ("minecraft:magic", "magic"),
+    ("minecraft:wither", "wither"),
+    ("minecraft:dragon_breath", "dragonBreath"),
+    ("minecraft:dry_out", "dryout"),
+    ("minecraft:sweet_berry_bush", "sweetBerryBush"),
+    ("minecraft:freeze", "freeze"),
+    ("minecraft:stalagmite", "stalagmite"),
+    ("minecraft:falling_block", "fallingBlock"),
+    ("minecraft:falling_anvil", "anvil"),
+    ("minecraft:falling_stalactite", "fallingStalactite"),
+    ("minecraft:sting", "sting"),
+    ("minecraft:mob_attack", "mob"),
+    ("minecraft:mob_attack_no_aggro", "mob"),
+    ("minecraft:player_attack", "player"),
+    ("minecraft:arrow", "arrow"),
+    ("minecraft:trident", "trident"),
+    ("minecraft:mob_projectile", "mob"),
+    ("minecraft:fireworks", "fireworks"),
+    ("minecraft:fireball", "fireball"),
+    ("minecraft:unattributed_fireball", "onFire"),
+    ("minecraft:wither_skull", "witherSkull"),
+    ("minecraft:thrown", "thrown"),
+    ("minecraft:indirect_magic", "indirectMagic"),
+    ("minecraft:thorns", "thorns"),
+    ("minecraft:explosion", "explosion"),
+    ("minecraft:player_explosion", "explosion.player"),
+    ("minecraft:sonic_boom", "sonic_boom"),
+    ("minecraft:bad_respawn_point", "badRespawnPoint"),
+];
+
+/// Damage types added in 1.20
+const DAMAGE_TYPES_763: [(&str, &str); 2] = [("minecraft:outside_border", "outsideBorder"), ("minecraft:generic_kill", "genericKill")];
+
+fn registry(kind: &str, entries: Vec<(&str, Compound)>) -> Compound {
+    let value = entries.into_iter().enumerate().map(|(id, (name, element))| compound! { "name" => name, "id" => id as i32, "element" => element }).collect::<Vec<_>>();
+
+    compound! { "type" => kind, "value" => if value.is_empty() { List::End } else { List::Compound(value) } }
+}
+
+/// The least the client accepts as the registries of a world: an overworld,
+/// plains, plain chat and the damage types
+fn registry_codec(protocol: i32) -> Compound {
+    let dimension = compound! { "piglin_safe" => 0i8, "has_raids" => 0i8, "monster_spawn_light_level" => 0, "monster_spawn_block_light_limit" => 0, "natural" => 1i8, "ambient_light" => 0f32, "infiniburn" => "#minecraft:infiniburn_overworld", "respawn_anchor_works" => 0i8, "has_skylight" => 1i8, "bed_works" => 0i8, "effects" => "minecraft:overworld", "min_y" => 0, "height" => 256, "logical_height" => 256, "coordinate_scale" => 1f64, "ultrawarm" => 0i8, "has_ceiling" => 0i8 };
+
+    let biome = compound! { "has_precipitation" => 0i8, "temperature" => 0.8f32, "downfall" => 0.4f32, "effects" => compound! { "sky_color" => 7907327, "water_fog_color" => 329011, "fog_color" => 12638463, "water_color" => 4159204 } };
+
+    let decoration = |key: &str| compound! { "translation_key" => key, "parameters" => List::String(vec!["sender".to_string(), "content".to_string()]) };
+    let chat = compound! { "chat" => decoration("chat.type.text"), "narration" => decoration("chat.type.text.narrate") };
+
+    let damage_types = DAMAGE_TYPES.iter().chain(if protocol >= 763 { DAMAGE_TYPES_763.iter() } else { [].iter() });
+    let damage_types = damage_types.map(|(name, message)| (*name, compound! { "message_id" => *message, "scaling" => "when_caused_by_living_non_player", "exhaustion" => 0f32 })).collect();
+
+    let mut codec = compound! { "minecraft:dimension_type" => registry("minecraft:dimension_type", vec![("minecraft:overworld", dimension)]), "minecraft:worldgen/biome" => registry("minecraft:worldgen/biome", vec![("minecraft:plains", biome)]), "minecraft:chat_type" => registry("minecraft:chat_type", vec![("minecraft:chat", chat)]), "minecraft:damage_type" => registry("minecraft:damage_type", damage_types) };
+
+    if protocol >= 763 {
+        codec.insert("minecraft:trim_pattern", registry("minecraft:trim_pattern", Vec::new()));
+        codec.insert("minecraft:trim_material", registry("minecraft:trim_material", Vec::new()));
+    }
+
+    codec
+}
+
+/// Logs the client in and drops it in an empty world, in spectator so the
+/// client doesn't wait for chunks that will never come
+pub fn join(username: &str, protocol: i32) -> BytesMut {
+    let mut out = BytesMut::new();
+
+    out.unsplit(make_bytes!(s2c::LoginSuccess { uuid: offline_uuid(username), username: username.to_string(), properties: Vec::new() }));
+    out.unsplit(make_bytes!(s2c::JoinGame {
+        entity_id: 1,
+        hardcore: false,
+        game_mode: 3, // Spectator
+        previous_game_mode: -1,
+        dimensions: vec!["vigilantguard:limbo".to_string()],
+        registry_codec: registry_codec(protocol),
+        dimension_type: "minecraft:overworld".to_string(),
+        dimension: "vigilantguard:limbo".to_string(),
+        hashed_seed: 0,
+        max_players: VarInt(1),
+        view_distance: VarInt(2),
+        simulation_distance: VarInt(2),
+        reduced_debug_info: false,
+        respawn_screen: false,
+        debug: false,
+        flat: true,
+        death_location: None,
+        portal_cooldown: Trailing((protocol >= 763).then_some(VarInt(0))),
+    }));
+    out.unsplit(make_bytes!(s2c::SpawnPosition { position: 64, angle: 0.0 }));
+    out.unsplit(make_bytes!(s2c::PlayerPosition { x: 0.0, y: 64.0, z: 0.0, yaw: 0.0, pitch: 0.0, flags: 0, teleport_id: VarInt(1) }));
+    // Until cleared, without overflowing the sum of the three
+    out.unsplit(make_bytes!(s2c::TitleTimes { fade_in: 10, stay: i32::MAX / 2, fade_out: 10 }));
+
+    out
+}
+
+/// Reads a frame from the server, uncompressed
+async fn read_frame(server: &mut TcpStream, compression: bool) -> anyhow::Result<Vec<u8>> {
+    let mut header = Vec::new();
+
+    let len = loop {
+        header.push(server.read_u8().await?);
+        if let Some((len, _)) = read_var_int(&header)? {
+            break len;
+        }
+    };
+
+    let limit = VIGILANT_CONFIG.load().guardian.limits.play;
+    anyhow::ensure!(len >= 0 && (!limits::active() || len as usize <= limit), "The server sent a frame of {len} bytes, the limit is {limit}");
+
+    let mut frame = vec![0; len as usize];
+    server.read_exact(&mut frame).await?;
+
+    if !compression {
+        return Ok(frame);
+    }
+
+    uncompress(&frame, true)
+}
+
+/// Logs into the server at `address` with the client's own handshake and
+/// login start, stopping right before the server's first play packet
+async fn transfer(address: String, login: &[u8]) -> anyhow::Result<Attempt> {
+    let mut server = TcpStream::connect(&address).await?;
+    server.set_nodelay(true)?;
+    server.write_all(login).await?;
+
+    let mut compression = false;
+
+    loop {
+        let frame = read_frame(&mut server, compression).await?;
+        let mut data = frame.as_slice();
+
+        match VarInt::decode(&mut data)?.0 {
+            0x00 => return Ok(Attempt::Refused(Text::decode(&mut data)?)),
+            0x01 => return Ok(Attempt::Encrypted),
+            0x02 => return Ok(Attempt::Joined(server, compression)),
+            0x03 => compression = VarInt::decode(&mut data)?.0 >= 0,
+            0x04 => {
+                // Login plugin request, which the proxy doesn't understand
+                let message_id = VarInt::decode(&mut data)?;
+
+                let mut response = Vec::new();
+                VarInt(0x02).encode(&mut response)?;
+                message_id.encode(&mut response)?;
+                false.encode(&mut response)?;
+
+                let mut frame = Vec::new();
+                VarInt(response.len() as i32 + compression as i32).encode(&mut frame)?;
+                if compression {
+                    VarInt(0).encode(&mut frame)?;
+                }
+                frame.extend_from_slice(&response);
+
+                server.write_all(&frame).await?;
+            }
+            id => anyhow::bail!("Unexpected login packet {id:#04x} from the server"),
+        }
+    }
+}
+
+/// A client the limbo takes in
+pub struct Client {
+    pub reader: OwnedReadHalf,
+    pub writer: OwnedWriteHalf,
+    pub address: SocketAddr,
+    pub username: String,
+    /// What it waits in the queue as
+    pub uuid: Uuid,
+    pub protocol: i32,
+    /// What it sent to get there, the handshake and the login start as
+    /// they'd go to the server
+    pub login: BytesMut,
+}
+
+/// Holds a client that logged in while the server was down or while it
+/// waits in the queue, until the server takes it or the client leaves.
+/// `slot` is its place on the server if it already has one.
+pub async fn hold(client: Client, listener: &ListenerConfig, slot: Option<Slot>, kick: &mut UnboundedReceiver<String>) -> anyhow::Result<Option<Transferred>> {
+    let Client { reader: mut client_reader, writer: mut client_writer, address, username, uuid, protocol, login } = client;
+    // Only the health of `[server]` is watched, others are just tried
+    let watched = listener::default_server(listener);
+    let mut place = Place { username: &username, slot };
+
+    client_writer.write_all(&join(&username, protocol)).await?;
+
+    match place.slot {
+        Some(_) => info!("{}", coloriser!("[/c(dark_blue){}c(reset)] Holding in the limbo while the server is offline", address)),
+        None => info!("{}", coloriser!("[/c(dark_blue){}c(reset)] Holding in the limbo while waiting in the queue", address)),
+    }
+
+    let mut keep_alive = tokio::time::interval(Duration::from_secs(10));
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    let mut next_attempt = Instant::now();
+    let mut title = String::new();
+
+    // What the client sends is thrown away, only whole frames so the server
+    // doesn't get half of one after the transfer
+    let mut buf = BytesMut::new();
+
+    // The login to the server runs next to the keep alives, so a server that
+    // is slow to answer doesn't time the client out. The client isn't read
+    // meanwhile, what it sends goes to the server if it takes the player.
+    let mut attempt: Option<BoxFuture<anyhow::Result<Attempt>>> = None;
+
+    loop {
+        tokio::select! {
+            read = client_reader.read_buf(&mut buf), if attempt.is_none() => {
+                if read? == 0 {
+                    return Ok(None);
+                }
+
+                while let Some((len, header)) = read_var_int(&buf)? {
+                    limits::frame(address, Stage::Play, len as usize)?;
+
+                    if header + len as usize > buf.len() {
+                        break;
+                    }
+                    let _ = buf.split_to(header + len as usize);
+                }
+            }
+            Some(reason) = kick.recv() => {
+                client_writer.write_all(&make_bytes!(s2c::Disconnect { reason: Text::from(reason) })).await?;
+                return Ok(None);
+            }
+            _ = keep_alive.tick() => {
+                client_writer.write_all(&make_bytes!(s2c::KeepAlive { id: chrono::Utc::now().timestamp_millis() })).await?;
+            }
+            _ = tick.tick() => {
+                let lang = VIGILANT_LANG.load();
+
+                if place.slot.is_none() {
+                    match queue::admit(&username, uuid) {
+                        Ok(admitted) => {
+                            info!("{}", coloriser!("[/c(dark_blue){}c(reset)] Got a place on the server from the queue", address));
+                            place.slot = Some(admitted);
+                        }
+                        Err(position) => {
+                            let actionbar = lang.queue_actionbar.replace("{position}", &position.to_string());
+                            show(&mut client_writer, &mut title, &lang.queue_title, &actionbar).await?;
+                            continue;
+                        }
+                    }
+                }
+
+                show(&mut client_writer, &mut title, &lang.limbo_title, &lang.limbo_actionbar).await?;
+
+                if (watched && !SERVER_ALIVE.load(Ordering::Relaxed)) || Instant::now() < next_attempt || !buf.is_empty() || attempt.is_some() {
+                    continue;
+                }
+
+                let timeout = VIGILANT_CONFIG.load().guardian.timeouts.login;
+                attempt = Some(Box::pin(deadline(timeout, "the server to log the player in", transfer(listener::server(listener), &login))));
+            }
+            result = async { attempt.as_mut().unwrap().await }, if attempt.is_some() => {
+                attempt = None;
+
+                match result {
+                    Ok(Attempt::Joined(server, compression)) => {
+                        client_writer.write_all(&make_bytes!(s2c::ClearTitles { reset: true })).await?;
+                        info!("{}", coloriser!("[/c(dark_blue){}c(reset)] Sent from the limbo to the server", address));
+
+                        let slot = place.slot.take().unwrap();
+                        return Ok(Some(Transferred { client_reader, client_writer, server, compression, slot }));
+                    }
+                    Ok(Attempt::Refused(reason)) => {
+                        client_writer.write_all(&make_bytes!(s2c::Disconnect { reason })).await?;
+                        info!("{}", coloriser!("[/c(dark_blue){}c(reset)] The server refused the player from the limbo", address));
+
+                        return Ok(None);
+                    }
+                    Ok(Attempt::Encrypted) => {
+                        client_writer.write_all(&make_bytes!(s2c::Disconnect { reason: Text::from(VIGILANT_LANG.load().limbo_transfer_kick.clone()) })).await?;
+                        warn!("{}", coloriser!("[/c(dark_blue){}c(reset)] The server asked for encryption, only offline mode servers can take players from the limbo", address));
+
+                        return Ok(None);
+                    }
+                    Err(err) => {
+                        warn!("{}", coloriser!("[/c(dark_blue){}c(reset)] Failed to send the player from the limbo: {}", address, err.to_string()));
+                        next_attempt = Instant::now() + RETRY_AFTER;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The place of a held player, given up in the queue when it leaves before
+/// getting on the server
+struct Place<'a> {
+    username: &'a str,
+    slot: Option<Slot>,
+}
+
+impl Drop for Place<'_> {
+    fn drop(&mut self) {
+        if self.slot.is_none() {
+            queue::leave(self.username);
+        }
+    }
+}
+
+/// Sends the action bar, and the title when it changed
+pub async fn show(client_writer: &mut OwnedWriteHalf, shown: &mut String, title: &str, actionbar: &str) -> anyhow::Result<()> {
+    if shown != title {
+        client_writer.write_all(&make_bytes!(s2c::Title { text: Text::from(title.to_string()) })).await?;
+        *shown = title.to_string();
+    }
+
+    client_writer.write_all(&make_bytes!(s2c::ActionBar { text: Text::from(actionbar.to_string()) })).await?;
+    Ok(())
+}
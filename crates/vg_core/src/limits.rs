@@ -0,0 +1,189 @@
+//! Limits on what a client may send, from `guardian.limits`. A client going
+//! over one of them is disconnected, and an IP that does so
+//! `guardian.limits.ban_after` times is banned for
+//! `guardian.limits.ban_duration` seconds.
+//!
+//! Frames are checked against the limit of the stage the session is in as
+//! soon as their length is read, before anything of them is buffered. Play
+//! frames can only be checked while the session isn't encrypted.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::warn;
+use once_cell::sync::Lazy;
+
+use crate::file::VIGILANT_CONFIG;
+use crate::macros::coloriser;
+use crate::{guardian, metrics};
+
+static VIOLATIONS: Lazy<Mutex<HashMap<String, (u32, Instant)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Clone, Copy)]
+pub enum Stage {
+    Handshake,
+    Status,
+    Login,
+    Play,
+}
+
+#[derive(Clone, Copy)]
+pub enum Violation {
+    OversizedFrame,
+    OversizedBuffer,
+    LongHostname,
+    LongUsername,
+    Malformed,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Handshake => "handshake",
+            Stage::Status => "status",
+            Stage::Login => "login",
+            Stage::Play => "play",
+        }
+    }
+
+    fn frame_limit(&self) -> usize {
+        let config = VIGILANT_CONFIG.load();
+        let limits = &config.guardian.limits;
+
+        match self {
+            Stage::Handshake => limits.handshake,
+            Stage::Status => limits.status,
+            Stage::Login => limits.login,
+            Stage::Play => limits.play,
+        }
+    }
+}
+
+impl Violation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Violation::OversizedFrame => "oversized_frame",
+            Violation::OversizedBuffer => "oversized_buffer",
+            Violation::LongHostname => "long_hostname",
+            Violation::LongUsername => "long_username",
+            Violation::Malformed => "malformed",
+        }
+    }
+}
+
+/// Counts a violation against the IP, banning it once it had too many, and
+/// returns the error to disconnect with
+pub fn violation(address: SocketAddr, violation: Violation, stage: Stage, detail: impl std::fmt::Display) -> anyhow::Error {
+    let config = VIGILANT_CONFIG.load();
+    let limits = &config.guardian.limits;
+    let ip = guardian::ip_key(address.ip());
+
+    metrics::violation(violation, stage);
+
+    if limits.ban_after > 0 {
+        let mut violations = VIOLATIONS.lock().unwrap();
+        let ban_duration = Duration::from_secs(limits.ban_duration);
+        violations.retain(|_, (_, last)| last.elapsed() < ban_duration);
+
+        let (count, last) = violations.entry(ip.clone()).or_insert((0, Instant::now()));
+        *count += 1;
+        *last = Instant::now();
+
+        if *count >= limits.ban_after {
+            violations.remove(&ip);
+            guardian::temp_ban(&ip, ban_duration);
+            warn!("{}", coloriser!("[/c(dark_blue){}c(reset)] Banned for {} seconds after {} violations", address, limits.ban_duration, limits.ban_after));
+        }
+    }
+
+    anyhow::anyhow!("Violated the {} limits ({}): {}", stage.as_str(), violation.as_str(), detail)
+}
+
+pub fn active() -> bool {
+    VIGILANT_CONFIG.load().guardian.limits.active
+}
+
+/// Checks the length a client announced for its next frame
+pub fn frame(address: SocketAddr, stage: Stage, len: usize) -> anyhow::Result<()> {
+    let limit = stage.frame_limit();
+
+    if active() && len > limit {
+        return Err(violation(address, Violation::OversizedFrame, stage, format!("frame of {len} bytes, the limit is {limit}")));
+    }
+
+    Ok(())
+}
+
+/// Checks how much a client sent that wasn't handled yet
+pub fn buffer(address: SocketAddr, stage: Stage, len: usize) -> anyhow::Result<()> {
+    let limit = VIGILANT_CONFIG.load().guardian.limits.buffer;
+
+    if active() && len > limit {
+        return Err(violation(address, Violation::OversizedBuffer, stage, format!("{len} bytes buffered, the limit is {limit}")));
+    }
+
+    Ok(())
+}
+
+/// Checks the server address of a handshake, as the client sent it
+pub fn hostname(address: SocketAddr, hostname: &str) -> anyhow::Result<()> {
+    let limit = VIGILANT_CONFIG.load().guardian.limits.hostname;
+
+    if active() && hostname.chars().count() > limit {
+        return Err(violation(address, Violation::LongHostname, Stage::Handshake, format!("hostname of {} characters, the limit is {limit}", hostname.chars().count())));
+    }
+
+    Ok(())
+}
+
+pub fn username(address: SocketAddr, username: &str) -> anyhow::Result<()> {
+    let limit = VIGILANT_CONFIG.load().guardian.limits.username;
+
+    if active() && username.chars().count() > limit {
+        return Err(violation(address, Violation::LongUsername, Stage::Login, format!("username of {} characters, the limit is {limit}", username.chars().count())));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One IP per test so none of them gets to `ban_after` violations
+    fn address(last: u8) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, last], 25565))
+    }
+
+    #[test]
+    fn frames_up_to_the_stage_limit_pass() {
+        let address = address(10);
+
+        assert!(frame(address, Stage::Handshake, 2048).is_ok());
+        assert!(frame(address, Stage::Handshake, 2049).is_err());
+        assert!(frame(address, Stage::Status, 256).is_ok());
+        assert!(frame(address, Stage::Status, 257).is_err());
+        assert!(frame(address, Stage::Login, 4096).is_ok());
+        assert!(frame(address, Stage::Play, 2097151).is_ok());
+    }
+
+    #[test]
+    fn buffer_is_capped() {
+        let address = address(11);
+
+        assert!(buffer(address, Stage::Login, 16384).is_ok());
+        assert!(buffer(address, Stage::Login, 16385).is_err());
+    }
+
+    #[test]
+    fn names_count_characters_not_bytes() {
+        let address = address(12);
+
+        assert!(username(address, &"é".repeat(16)).is_ok());
+        assert!(username(address, &"a".repeat(17)).is_err());
+        assert!(hostname(address, &"é".repeat(255)).is_ok());
+        assert!(hostname(address, &"a".repeat(256)).is_err());
+    }
+}
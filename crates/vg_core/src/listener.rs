@@ -0,0 +1,277 @@
+//! The addresses the proxy accepts players on. `[proxy]` is the default
+//! listener, bound to `proxy.ip` and every one of `proxy.extra_ips`, and each
+//! `[[listeners]]` entry is another one with its own server, IP forwarding,
+//! PROXY protocol settings and guardian profile. Bans, limits and stats are
+//! shared by all of them.
+//!
+//! Behind a load balancer speaking the PROXY protocol, a session is known by
+//! the player's address from the header the load balancer sends first, not
+//! by the load balancer's own. Only peers in `proxy.trusted_proxies` are let
+//! send one, as whoever sends it picks the address the guardian sees.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::Lazy;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+
+use crate::file::config_file::ListenerConfig;
+use crate::file::VIGILANT_CONFIG;
+use crate::guardian::canonical_ip;
+use crate::{deadline, server_address};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest a v1 header can be, line break included
+const V1_MAX: usize = 107;
+
+/// Seconds to wait for the header when `guardian.timeouts.handshake` is 0, a
+/// load balancer sends it right away
+const HEADER_TIMEOUT: u64 = 5;
+
+/// Players' addresses by the address of the load balancer's connection
+static CLIENTS: Lazy<Mutex<HashMap<SocketAddr, SocketAddr>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Clone)]
+pub enum Source {
+    /// `[proxy]`, bound to `proxy.ip` or to the given one of `proxy.extra_ips`
+    Proxy(Option<String>),
+    Listener(Arc<ListenerConfig>),
+}
+
+impl Source {
+    /// Every listener the config asks for, `[proxy]` first
+    pub fn all() -> Vec<Source> {
+        let config = VIGILANT_CONFIG.load();
+
+        let extra = config.proxy.extra_ips.iter().map(|ip| Source::Proxy(Some(ip.clone())));
+        let listeners = config.listeners.iter().map(|listener| Source::Listener(Arc::new(listener.clone())));
+
+        std::iter::once(Source::Proxy(None)).chain(extra).chain(listeners).collect()
+    }
+
+    /// The settings as of now, the ones of `[proxy]` follow reloads
+    pub fn settings(&self) -> Arc<ListenerConfig> {
+        let config = VIGILANT_CONFIG.load();
+
+        match self {
+            Source::Proxy(ip) => Arc::new(ListenerConfig { name: "default".to_string(), ip: ip.clone().unwrap_or(config.proxy.ip.clone()), port: config.proxy.port, server: String::new(), ip_forward: None, proxy_protocol: config.proxy.proxy_protocol, profile: String::new() }),
+            Source::Listener(listener) => listener.clone(),
+        }
+    }
+
+    /// `IP:PORT` to bind, the IP in brackets if it is an IPv6 address
+    pub fn address(&self) -> String {
+        let settings = self.settings();
+
+        match settings.ip.contains(':') {
+            true => format!("[{}]:{}", settings.ip.trim_matches(|c| c == '[' || c == ']'), settings.port),
+            false => format!("{}:{}", settings.ip, settings.port),
+        }
+    }
+
+    /// Whether it is rebound when `proxy.ip` or `proxy.port` are reloaded
+    pub fn rebinds(&self) -> bool {
+        matches!(self, Source::Proxy(None))
+    }
+}
+
+/// The server players of `listener` go to
+pub fn server(listener: &ListenerConfig) -> String {
+    match listener.server.is_empty() {
+        true => server_address(),
+        false => listener.server.clone(),
+    }
+}
+
+/// Whether `listener` goes to `[server]`, the one whose status is cached and
+/// whose health is watched
+pub fn default_server(listener: &ListenerConfig) -> bool {
+    listener.server.is_empty()
+}
+
+pub fn ip_forward(listener: &ListenerConfig) -> bool {
+    listener.ip_forward.unwrap_or(VIGILANT_CONFIG.load().proxy.forwarder.ip_forward)
+}
+
+/// Reads the PROXY protocol header when `listener` expects one, returning the
+/// player's address
+pub async fn accept(stream: &mut TcpStream, listener: &ListenerConfig) -> anyhow::Result<SocketAddr> {
+    let peer = stream.peer_addr()?;
+
+    if !listener.proxy_protocol {
+        return Ok(peer);
+    }
+
+    let config = VIGILANT_CONFIG.load();
+    anyhow::ensure!(trusted(peer.ip(), &config.proxy.trusted_proxies), "Refused, {} isn't in proxy.trusted_proxies", peer.ip());
+
+    let timeout = match config.guardian.timeouts.handshake {
+        0 => HEADER_TIMEOUT,
+        timeout => timeout,
+    };
+    let client = deadline(timeout, "the PROXY protocol header", proxy_header(stream)).await?.unwrap_or(peer);
+
+    if client != peer {
+        CLIENTS.lock().unwrap().insert(peer, client);
+    }
+
+    Ok(client)
+}
+
+/// The player's address of a connection, `peer` being the address the
+/// connection came from
+pub fn client(peer: SocketAddr) -> SocketAddr {
+    CLIENTS.lock().unwrap().get(&peer).copied().unwrap_or(peer)
+}
+
+/// Forgets the player's address of a closed connection
+pub fn forget(peer: SocketAddr) {
+    CLIENTS.lock().unwrap().remove(&peer);
+}
+
+/// Whether `ip` is one of `proxies`, given as IPs or as ranges like
+/// `10.0.0.0/8`
+fn trusted(ip: IpAddr, proxies: &[String]) -> bool {
+    let ip = canonical_ip(ip);
+
+    proxies.iter().any(|proxy| {
+        let (range, bits) = proxy.split_once('/').unwrap_or((proxy, ""));
+
+        match (range.parse::<IpAddr>().map(canonical_ip), ip) {
+            (Ok(IpAddr::V4(range)), IpAddr::V4(ip)) => in_range(u32::from(range) as u128, u32::from(ip) as u128, 32, bits),
+            (Ok(IpAddr::V6(range)), IpAddr::V6(ip)) => in_range(u128::from(range), u128::from(ip), 128, bits),
+            _ => false,
+        }
+    })
+}
+
+/// Whether `ip` shares the first `bits` of its `width` bits with `range`, all
+/// of them when `bits` is empty
+fn in_range(range: u128, ip: u128, width: u32, bits: &str) -> bool {
+    let bits = match bits {
+        "" => width,
+        bits => match bits.parse::<u32>() {
+            Ok(bits) if bits <= width => bits,
+            _ => return false,
+        },
+    };
+    let mask = u128::MAX.checked_shl(width - bits).unwrap_or(0);
+
+    range & mask == ip & mask
+}
+
+/// Reads a v1 or v2 header, `None` when the load balancer connected on its
+/// own behalf, like for a health check
+async fn proxy_header<R: AsyncRead + Unpin>(stream: &mut R) -> anyhow::Result<Option<SocketAddr>> {
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).await?;
+
+        let mut data = vec![0; u16::from_be_bytes([header[2], header[3]]) as usize];
+        stream.read_exact(&mut data).await?;
+
+        anyhow::ensure!(header[0] >> 4 == 2, "Unsupported PROXY protocol version {}", header[0] >> 4);
+
+        // The LOCAL command
+        if header[0] & 0x0F == 0 {
+            return Ok(None);
+        }
+
+        return Ok(match header[1] >> 4 {
+            0x1 if data.len() >= 12 => Some(SocketAddr::new(Ipv4Addr::new(data[0], data[1], data[2], data[3]).into(), u16::from_be_bytes([data[8], data[9]]))),
+            0x2 if data.len() >= 36 => Some(SocketAddr::new(Ipv6Addr::from(<[u8; 16]>::try_from(&data[..16])?).into(), u16::from_be_bytes([data[32], data[33]]))),
+            _ => None,
+        });
+    }
+
+    anyhow::ensure!(start.starts_with(b"PROXY "), "Expected a PROXY protocol header");
+
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        anyhow::ensure!(line.len() < V1_MAX, "PROXY protocol header is too long");
+        line.push(stream.read_u8().await?);
+    }
+
+    match std::str::from_utf8(&line)?.trim_end().split(' ').collect::<Vec<_>>()[..] {
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => Ok(Some(SocketAddr::new(source.parse()?, port.parse()?))),
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        _ => anyhow::bail!("Malformed PROXY protocol header"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(header: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+        proxy_header(&mut &header[..]).await
+    }
+
+    fn v2(command: u8, family: u8, data: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend((data.len() as u16).to_be_bytes());
+        header.extend(data);
+        header
+    }
+
+    #[tokio::test]
+    async fn parses_v1() {
+        assert_eq!(parse(b"PROXY TCP4 192.0.2.7 198.51.100.1 51234 25565\r\n").await.unwrap(), Some("192.0.2.7:51234".parse().unwrap()));
+        assert_eq!(parse(b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 25565\r\n").await.unwrap(), Some("[2001:db8::7]:51234".parse().unwrap()));
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_v1() {
+        assert!(parse(b"GET / HTTP/1.1\r\n").await.is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.7 198.51.100.1\r\n").await.is_err());
+        assert!(parse(b"PROXY TCP4 not.an.ip 198.51.100.1 51234 25565\r\n").await.is_err());
+        assert!(parse(format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX)).as_bytes()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn parses_v2() {
+        let v4 = [192, 0, 2, 7, 198, 51, 100, 1, 0xC8, 0x22, 0x63, 0xDD];
+        assert_eq!(parse(&v2(0x1, 0x11, &v4)).await.unwrap(), Some("192.0.2.7:51234".parse().unwrap()));
+
+        let mut v6 = "2001:db8::7".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        v6.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        v6.extend([0xC8, 0x22, 0x63, 0xDD]);
+        assert_eq!(parse(&v2(0x1, 0x21, &v6)).await.unwrap(), Some("[2001:db8::7]:51234".parse().unwrap()));
+
+        assert_eq!(parse(&v2(0x0, 0x00, &[])).await.unwrap(), None);
+        assert_eq!(parse(&v2(0x1, 0x11, &v4[..8])).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_v2() {
+        let mut header = v2(0x1, 0x11, &[0; 12]);
+        header[12] = 0x11;
+        assert!(parse(&header).await.is_err());
+
+        let header = v2(0x1, 0x11, &[0; 12]);
+        assert!(parse(&header[..20]).await.is_err());
+    }
+
+    #[test]
+    fn trusts_listed_peers() {
+        let proxies = ["10.0.0.0/8".to_string(), "192.0.2.7".to_string(), "2001:db8::/32".to_string()];
+
+        assert!(trusted("10.1.2.3".parse().unwrap(), &proxies));
+        assert!(trusted("192.0.2.7".parse().unwrap(), &proxies));
+        assert!(trusted("::ffff:192.0.2.7".parse().unwrap(), &proxies));
+        assert!(trusted("2001:db8:ffff::1".parse().unwrap(), &proxies));
+        assert!(!trusted("192.0.2.8".parse().unwrap(), &proxies));
+        assert!(!trusted("11.0.0.1".parse().unwrap(), &proxies));
+        assert!(!trusted("2001:db9::1".parse().unwrap(), &proxies));
+        assert!(!trusted("10.0.0.1".parse().unwrap(), &["10.0.0.0/33".to_string()]));
+        assert!(!trusted("10.0.0.1".parse().unwrap(), &[]));
+    }
+}
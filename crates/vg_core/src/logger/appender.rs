@@ -1,4 +1,4 @@
-use std::io::BufWriter;
+use std::io::Write;
 use std::sync::Mutex;
 
 use log4rs::append::Append;
@@ -14,16 +14,16 @@ pub struct LogAppender<T: FnMut(String) + Sync + Send + 'static> {
 
 impl<F: FnMut(String) + Sync + Send + 'static> Append for LogAppender<F> {
     fn append(&self, record: &log::Record) -> anyhow::Result<()> {
-        let mut writer = SimpleWriter(BufWriter::new(Vec::new()));
+        let mut writer = SimpleWriter(Vec::new());
         self.encoder.encode(&mut writer, record).unwrap();
-        let str = String::from_utf8_lossy(writer.0.buffer());
+        let str = String::from_utf8_lossy(&writer.0);
         let color = match record.level() {
             log::Level::Error => "\x1b[1;31m",
             log::Level::Warn => "\x1b[0;33m",
             log::Level::Info => "\x1b[1;32m",
             _ => "",
         };
-        if VIGILANT_CONFIG.colorize {
+        if VIGILANT_CONFIG.load().colorize {
             (self.printer.lock().unwrap())(str.to_string().replace(record.level().as_str(), format!("{}{}\x1b[0m", color, record.level().as_str()).as_str()));
         } else {
             (self.printer.lock().unwrap())(str.to_string());
@@ -32,7 +32,7 @@ impl<F: FnMut(String) + Sync + Send + 'static> Append for LogAppender<F> {
     }
 
     fn flush(&self) {
-        todo!()
+        let _ = std::io::stdout().flush();
     }
 }
 
@@ -0,0 +1,71 @@
+//! The log file, rolled over by size and age into numbered archives that are
+//! gzipped when their name ends in `.gz`.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::Trigger;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::{LogFile, RollingFileAppender};
+use log4rs::encode::pattern::PatternEncoder;
+use log4rs::encode::writer::simple::SimpleWriter;
+use log4rs::encode::Encode;
+
+use super::strip_ansi;
+use crate::file::config_file::LogFileConfig;
+
+const PATTERN: &str = "[{d(%Y-%m-%d %H:%M:%S)}] [{T}/{l}]: {m}{n}";
+
+pub fn appender(config: &LogFileConfig) -> anyhow::Result<RollingFileAppender> {
+    let archive = format!("{}.{{}}{}", config.path, if config.gzip { ".gz" } else { "" });
+    let roller = FixedWindowRoller::builder().build(&archive, config.keep)?;
+
+    let trigger = RotationTrigger { max_size: config.max_size * 1_000_000, interval: (config.interval > 0).then(|| Duration::from_secs(config.interval * 3600)), rolled_at: Mutex::new(Instant::now()) };
+    let policy = CompoundPolicy::new(Box::new(trigger), Box::new(roller));
+
+    Ok(RollingFileAppender::builder().encoder(Box::new(StripAnsi(Box::new(PatternEncoder::new(PATTERN))))).build(&config.path, Box::new(policy))?)
+}
+
+/// Rolls the file once it grows past `max_size` or `interval` has passed
+/// since the last roll. The age counts from startup, not from the file's
+/// creation.
+#[derive(Debug)]
+struct RotationTrigger {
+    max_size: u64,
+    interval: Option<Duration>,
+    rolled_at: Mutex<Instant>,
+}
+
+impl Trigger for RotationTrigger {
+    fn trigger(&self, file: &LogFile) -> anyhow::Result<bool> {
+        let mut rolled_at = self.rolled_at.lock().unwrap();
+
+        let oversized = self.max_size > 0 && file.len_estimate() > self.max_size;
+        let expired = self.interval.is_some_and(|v| rolled_at.elapsed() >= v);
+
+        if oversized || expired {
+            *rolled_at = Instant::now();
+        }
+
+        Ok(oversized || expired)
+    }
+
+    fn is_pre_process(&self) -> bool {
+        false
+    }
+}
+
+/// Drops the terminal colors from whatever the inner encoder writes
+#[derive(Debug)]
+struct StripAnsi(Box<dyn Encode>);
+
+impl Encode for StripAnsi {
+    fn encode(&self, w: &mut dyn log4rs::encode::Write, record: &log::Record) -> anyhow::Result<()> {
+        let mut writer = SimpleWriter(Vec::new());
+        self.0.encode(&mut writer, record)?;
+
+        w.write_all(strip_ansi(&String::from_utf8_lossy(&writer.0)).as_bytes())?;
+        Ok(())
+    }
+}
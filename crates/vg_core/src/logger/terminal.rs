@@ -1,21 +1,106 @@
-use std::collections::VecDeque;
-use std::sync::atomic::Ordering;
 use std::sync::Mutex;
 use std::thread;
 
-use log::{error, info, LevelFilter};
-use log4rs::config::{Appender, Root};
+use log::{error, info};
+use log4rs::config::{Appender, Logger, Root};
 use log4rs::encode::pattern::PatternEncoder;
 use log4rs::Config;
+use rustyline::completion::Completer;
 use rustyline::error::ReadlineError;
-use rustyline::{DefaultEditor, ExternalPrinter};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, ExternalPrinter, Helper};
 
 use super::appender::LogAppender;
+use super::file;
+use crate::cli::ARGS;
+use crate::command::{self, ArgKind, Permission, COMMANDS};
+use crate::file::VIGILANT_CONFIG;
 use crate::macros::coloriser;
-use crate::{CONNECTIONS, PLAYERS, RUNTIME, TOTAL_DOWNLOAD, TOTAL_UPLOAD};
+use crate::{queue, CONNECTIONS, PLAYERS, RUNTIME};
+
+/// Completes and hints the console commands from their declared arguments
+struct ConsoleHelper;
+
+impl ConsoleHelper {
+    fn candidates(kind: &ArgKind) -> Vec<String> {
+        match kind {
+            ArgKind::Choice(options) => options.iter().map(|v| v.to_string()).collect(),
+            ArgKind::Command => COMMANDS.iter().map(|v| v.name.to_string()).collect(),
+            ArgKind::Player => PLAYERS.blocking_lock().values().cloned().collect(),
+            ArgKind::Waiting => queue::with_waiting(|waiting| waiting.iter().map(|v| v.username.clone()).collect()),
+            ArgKind::Ip => CONNECTIONS.blocking_lock().keys().cloned().collect(),
+            ArgKind::Text => Vec::new(),
+        }
+    }
+}
+
+impl Completer for ConsoleHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let words = line.split(' ').collect::<Vec<&str>>();
+        let word = words[words.len() - 1];
+        let start = pos - word.len();
+
+        let candidates = if words.len() == 1 {
+            COMMANDS.iter().flat_map(|v| std::iter::once(&v.name).chain(v.aliases)).map(|v| v.to_string()).collect()
+        } else {
+            match command::find(words[0]).and_then(|v| v.args.get(words.len() - 2)) {
+                Some(arg) => Self::candidates(&arg.kind),
+                None => Vec::new(),
+            }
+        };
+
+        let mut candidates = candidates.into_iter().filter(|v| v.starts_with(word)).collect::<Vec<String>>();
+        candidates.sort();
+        candidates.dedup();
+
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ConsoleHelper {
+    type Hint = String;
+
+    /// The arguments still missing once a command is typed out
+    fn hint(&self, line: &str, pos: usize, _: &Context<'_>) -> Option<String> {
+        if pos < line.len() || !line.ends_with(' ') {
+            return None;
+        }
+
+        let words = line.split_whitespace().collect::<Vec<&str>>();
+        let command = command::find(words.first()?)?;
+        let usage = command.usage();
+        let args = usage.split(' ').skip(words.len()).collect::<Vec<&str>>();
+
+        if args.is_empty() {
+            return None;
+        }
+
+        Some(args.join(" "))
+    }
+}
+
+impl Highlighter for ConsoleHelper {}
+
+impl Validator for ConsoleHelper {}
+
+impl Helper for ConsoleHelper {}
 
 pub fn setup() -> Result<(), ()> {
-    let mut rl = DefaultEditor::new().unwrap();
+    if ARGS.no_console {
+        return init_logger(|v| print!("{v}"));
+    }
+
+    let history = ARGS.data_dir.join("console_history.txt");
+
+    let mut rl = Editor::<ConsoleHelper, DefaultHistory>::new().unwrap();
+    rl.set_helper(Some(ConsoleHelper));
+    let _ = rl.load_history(&history);
     let mut printer = rl.create_external_printer().unwrap();
 
     thread::Builder::new()
@@ -25,68 +110,19 @@ pub fn setup() -> Result<(), ()> {
             match line {
                 Ok(line) => {
                     rl.add_history_entry(&line).unwrap();
+                    if let Err(err) = rl.save_history(&history) {
+                        error!("{}", coloriser!("Failed to save the console history: {}", err.to_string()));
+                    }
 
-                    let mut line = line.split(" ").collect::<VecDeque<&str>>();
-                    let cmd = line.pop_front().unwrap_or(&"");
-                    let args = line;
-
-                    match cmd {
-                        "stop" | "exit" => {
-                            info!("{}", coloriser!("c(bright_red)Stopping"));
-                            std::process::exit(0);
-                        }
-                        "list" => {
-                            let list_type = args.get(0).unwrap_or(&&"");
-
-                            match *list_type {
-                                "connection" | "conn" => {
-                                    RUNTIME.spawn(async move {
-                                        let lock = CONNECTIONS.lock().await;
-                                        info!("{} Connections: {:?}", lock.len(), lock);
-                                    });
-                                }
-                                "player" => {
-                                    RUNTIME.spawn(async move {
-                                        let lock = PLAYERS.lock().await;
-                                        let list = lock.values().collect::<Vec<&String>>();
-                                        info!("{} Players: {:?}", list.len(), list);
-                                    });
-                                }
-                                _ => {
-                                    if list_type.len() > 0 {
-                                        info!("Unknown subcommand {:?}", list_type);
-                                    } else {
-                                        info!("Usage: list [connection, player]");
-                                    }
-                                }
-                            }
-                        }
-                        "usage" => {
-                            let usage_type = args.get(0).unwrap_or(&&"");
-
-                            match *usage_type {
-                                "network" | "net" => unsafe {
-                                    info!("\x1b[1;32;42m ⬇ {}MB \x1b[0m\x1b[1;33;43m ⬆ {}MB ", TOTAL_DOWNLOAD.load(Ordering::Relaxed) / 1e+6, TOTAL_UPLOAD.load(Ordering::Relaxed) / 1e+6);
-                                },
-                                _ => {
-                                    if usage_type.len() > 0 {
-                                        info!("Unknown subcommand {:?}", usage_type);
-                                    } else {
-                                        info!("Usage: usage [network]");
-                                    }
-                                }
-                            }
-                        }
-                        _ => {
-                            if cmd.len() > 0 {
-                                info!("Unknown command {:?}", cmd);
-                            }
-                        }
+                    let output = RUNTIME.block_on(command::execute(&line, Permission::Manage));
+                    if !output.is_empty() {
+                        info!("{}", output);
                     }
                 }
                 Err(err) => {
                     if let ReadlineError::Interrupted = err {
-                        std::process::exit(0);
+                        crate::shutdown::stop();
+                        continue;
                     }
 
                     error!("{}", coloriser!("c(bright_red){}", err.to_string()));
@@ -95,11 +131,35 @@ pub fn setup() -> Result<(), ()> {
         })
         .unwrap();
 
+    init_logger(move |v| printer.print(v).unwrap())
+}
+
+fn init_logger<F: FnMut(String) + Sync + Send + 'static>(printer: F) -> Result<(), ()> {
     let patt = "[{d(%H:%M:%S)}] {([{T}/{h({l})}]):<12}: {m}\x1b[0m\n";
 
-    let stdout = LogAppender { printer: Mutex::new(move |v| printer.print(v).unwrap()), encoder: Box::new(PatternEncoder::new(patt)) };
+    let stdout = LogAppender { printer: Mutex::new(printer), encoder: Box::new(PatternEncoder::new(patt)) };
+
+    let vigilant_config = VIGILANT_CONFIG.load();
+    let logging = &vigilant_config.logging;
+
+    let mut config = Config::builder().appender(Appender::builder().build("stdout", Box::new(stdout)));
+    let mut root = Root::builder().appender("stdout");
+
+    if logging.file.active {
+        match file::appender(&logging.file) {
+            Ok(appender) => {
+                config = config.appender(Appender::builder().build("file", Box::new(appender)));
+                root = root.appender("file");
+            }
+            Err(err) => eprintln!("Failed to open the log file {}: {err:#}", logging.file.path),
+        }
+    }
+
+    for (module, level) in &logging.modules {
+        config = config.logger(Logger::builder().build(module, *level));
+    }
 
-    let config = Config::builder().appenders([Appender::builder().build("stdout", Box::new(stdout))]).build(Root::builder().appenders(["stdout"]).build(LevelFilter::Info)).unwrap();
+    let config = config.build(root.build(logging.level)).unwrap();
 
     let _handle = log4rs::init_config(config).unwrap();
 
@@ -1,196 +1,513 @@
+mod admin;
+mod captcha;
+mod cli;
+mod command;
+mod events;
 mod file;
+mod fingerprint;
 pub mod guardian;
 mod interceptor;
+mod limbo;
+mod limits;
+mod listener;
 mod logger;
 pub mod macros;
+mod metrics;
 pub mod packet;
-
+mod plugin;
+mod profile;
+mod queue;
+mod rcon;
+mod script;
+mod session;
+mod shutdown;
+mod status;
+mod traffic;
+mod vpn;
+
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::io::ErrorKind;
-use std::net::{SocketAddr, ToSocketAddrs};
-
+use std::future::Future;
+use std::net::SocketAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{fs, thread};
 
-use atomic_float::AtomicF64;
-
+use anyhow::Context;
+use events::EventKind;
+use fingerprint::Fingerprint;
 use interceptor::interceptor::Interceptor;
+use interceptor::pipe::{pipe, PipeState};
+use limits::Stage;
 use log::info;
 use logger::terminal;
 use once_cell::sync::Lazy;
 use packet::*;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use session::Session;
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime::Runtime;
-use tokio::sync::Mutex;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::{Mutex, Notify};
+use traffic::Traffic;
 use valence_protocol::bytes::BytesMut;
 use valence_protocol::decoder::PacketDecoder;
 use valence_protocol::encoder::PacketEncoder;
 use valence_protocol::packet::c2s::handshake::handshake::NextState;
+use valence_protocol::packet::s2c::login::LoginDisconnectS2c;
+use valence_protocol::text::Text;
+use valence_protocol::uuid::Uuid;
 
 use vg_macro::make_gatekeeper;
 
+use crate::file::config_file::ListenerConfig;
 use crate::file::{VIGILANT_CONFIG, VIGILANT_LANG};
 
 #[macro_use]
 extern crate lazy_static;
 
-static mut TOTAL_DOWNLOAD: AtomicF64 = AtomicF64::new(0.0);
-static mut TOTAL_UPLOAD: AtomicF64 = AtomicF64::new(0.0);
-
 static SERVER_ALIVE: AtomicBool = AtomicBool::new(false);
 
 static RUNTIME: Lazy<Runtime> = Lazy::new(|| tokio::runtime::Builder::new_multi_thread().enable_all().thread_name("proxy").build().expect("Failed to create a new runtime"));
 
 lazy_static! {
-    static ref IP_CACHE: Mutex<HashMap<i64, String>> = Mutex::new(HashMap::new());
+    /// IPs that pinged the server list, with when their ping stops counting
+    static ref IP_CACHE: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
     static ref CONNECTIONS: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
     static ref PLAYERS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+    static ref SESSIONS: Mutex<HashMap<String, Session>> = Mutex::new(HashMap::new());
+    static ref LISTENER_REBIND: Notify = Notify::new();
 }
 
-async fn proxy(client: TcpStream, server: TcpStream) -> anyhow::Result<()> {
-    let (client_reader, client_writer) = client.into_split();
+/// Records whether the server could be reached, returning whether that
+/// changed
+fn backend_alive(alive: bool) -> bool {
+    let changed = SERVER_ALIVE.swap(alive, Ordering::Relaxed) != alive;
+
+    if changed {
+        events::emit_global(if alive { EventKind::BackendUp } else { EventKind::BackendDown });
+    }
+
+    changed
+}
+
+/// Opens the backend connection once the client got through the gate, sending
+/// it what the client said so far. Resolved per connection so a reloaded
+/// backend only applies to new players.
+async fn connect_backend(listener: &ListenerConfig, c2s: &Mutex<Interceptor<'_>>, s2c: &Mutex<Interceptor<'_>>) -> anyhow::Result<bool> {
+    let connecting = Instant::now();
+    let address = listener::server(listener);
+    // Only `[server]` has its health watched
+    let watched = listener::default_server(listener);
+
+    let timeout = Duration::from_secs(VIGILANT_CONFIG.load().server.connect_timeout.max(1));
+    let connected = tokio::time::timeout(timeout, TcpStream::connect(&address)).await.unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out")));
+
+    let server = match connected {
+        Ok(server) => server,
+        Err(err) => {
+            if !watched || backend_alive(false) {
+                log::warn!("{}", colorizer!("Failed to connect to the server at c(on_blue) {} c(reset): {}", address, err.to_string()));
+            }
+            return Ok(false);
+        }
+    };
+
+    metrics::BACKEND_CONNECT.observe(connecting.elapsed());
+    server.set_nodelay(true)?;
+    if watched {
+        backend_alive(true);
+    }
+
     let (server_reader, server_writer) = server.into_split();
 
-    let c2s = Mutex::new(Interceptor { direction: PacketDirection::C2S, reader: Some(client_reader), writer: Some(server_writer), encoder: PacketEncoder::new(), decoder: PacketDecoder::new(), frame: BytesMut::new(), other: None });
-    let s2c = Mutex::new(Interceptor { direction: PacketDirection::S2C, reader: Some(server_reader), writer: Some(client_writer), encoder: PacketEncoder::new(), decoder: PacketDecoder::new(), frame: BytesMut::new(), other: None });
+    let mut c2s = c2s.lock().await;
+    let pending = std::mem::take(&mut c2s.pending);
+    c2s.writer.insert(server_writer).write_all(&pending).await?;
+    s2c.lock().await.reader = Some(server_reader);
+
+    Ok(true)
+}
+
+/// Runs a client through the gate, connecting to the backend only once there
+/// is something to ask it, then forwards the session if it logged in
+async fn proxy(client: TcpStream, address: SocketAddr, listener: &ListenerConfig, kick: &mut UnboundedReceiver<String>, traffic: (Arc<Traffic>, Arc<Traffic>), fingerprint: Arc<Fingerprint>) -> anyhow::Result<()> {
+    let (client_reader, client_writer) = client.into_split();
+
+    let c2s = Mutex::new(Interceptor { direction: PacketDirection::C2S, reader: Some(client_reader), writer: None, encoder: PacketEncoder::new(), decoder: PacketDecoder::new(), frame: BytesMut::new(), buffer: BytesMut::new(), stage: Stage::Handshake, pending: BytesMut::new(), passed_through: false, other: None });
+    let s2c = Mutex::new(Interceptor { direction: PacketDirection::S2C, reader: None, writer: Some(client_writer), encoder: PacketEncoder::new(), decoder: PacketDecoder::new(), frame: BytesMut::new(), buffer: BytesMut::new(), stage: Stage::Handshake, pending: BytesMut::new(), passed_through: false, other: None });
 
     c2s.lock().await.other = Some(&s2c);
     s2c.lock().await.other = Some(&c2s);
 
-    let next = make_gatekeeper!(c2s, Handshake).next_state;
+    let mut logging_in = false;
+    let mut protocol = None;
+    let mut limbo = None;
+    let mut slot = None;
+    let mut captcha = None;
+    let mut uuid = Uuid::nil();
+    // Copied out, the session shouldn't keep the config it started with alive
+    let (timeouts, kick_unsupported, queue_limbo) = {
+        let config = VIGILANT_CONFIG.load();
+        (config.guardian.timeouts, config.guardian.captcha.kick_unsupported, config.queue.limbo)
+    };
+
+    let gate = async {
+        let handshake = deadline(timeouts.handshake, "the handshake", async { Ok(make_gatekeeper!(c2s, Handshake)) }).await?;
+        logging_in = matches!(handshake.next_state, NextState::Login);
+
+        let mut interceptor = c2s.lock().await;
+        // Dropped for going over the limits
+        if !interceptor.passed_through {
+            return Ok(());
+        }
+        interceptor.stage = if logging_in { Stage::Login } else { Stage::Status };
+        drop(interceptor);
 
-    if !SERVER_ALIVE.load(Ordering::Relaxed) {
-        match next {
+        match handshake.next_state {
             NextState::Status => {
-                make_gatekeeper!(c2s, QueryRequest);
+                let status = async {
+                    make_gatekeeper!(c2s, QueryRequest);
+
+                    // Otherwise the proxy already answered in place of the server
+                    let forwarded = c2s.lock().await.passed_through;
+
+                    if forwarded && connect_backend(listener, &c2s, &s2c).await? {
+                        make_gatekeeper!(s2c, QueryResponse);
+                        make_gatekeeper!(c2s, QueryPing);
+                        make_gatekeeper!(s2c, QueryPong);
+
+                        return Ok(());
+                    }
+
+                    if forwarded {
+                        s2c.lock().await.writer.as_mut().unwrap().write_all(&interceptor::gate::offline_status()).await?;
+                    }
+
+                    c2s.lock().await.gatekeeper::<packet::c2s::QueryPing, _, _>(|packet, reader| async move { interceptor::gate::C2S::local_query_ping(packet, reader).await }).await?;
+
+                    Ok(())
+                };
+
+                deadline(timeouts.status, "the status exchange", status).await?;
             }
             NextState::Login => {
-                make_gatekeeper!(c2s, LoginHello);
-            }
-        }
+                let hello = deadline(timeouts.login, "the login", async { Ok(make_gatekeeper!(c2s, LoginHello)) }).await?;
 
-        return Ok(());
-    }
+                // Rejected, the disconnect was already sent
+                if !c2s.lock().await.passed_through {
+                    return Ok(());
+                }
+
+                if captcha::required(address).await {
+                    if limbo::speaks(handshake.protocol_version.0) {
+                        protocol = Some(handshake.protocol_version.0);
+                        captcha = Some(hello.username);
+                        return Ok(());
+                    }
+
+                    if kick_unsupported {
+                        let disconnect = make_bytes!(LoginDisconnectS2c { reason: Cow::Owned(Text::from(VIGILANT_LANG.load().captcha_unsupported_kick.clone())) });
+                        s2c.lock().await.writer.as_mut().unwrap().write_all(&disconnect).await?;
+                        return Ok(());
+                    }
+                }
 
-    match next {
-        NextState::Status => {
-            make_gatekeeper!(c2s, QueryRequest);
-            make_gatekeeper!(s2c, QueryResponse);
-            make_gatekeeper!(c2s, QueryPing);
-            make_gatekeeper!(s2c, QueryPong);
+                uuid = hello.profile_id.unwrap_or_else(|| limbo::offline_uuid(&hello.username));
+
+                match queue::admit(&hello.username, uuid) {
+                    Ok(admitted) => {
+                        slot = Some(admitted);
+
+                        if !connect_backend(listener, &c2s, &s2c).await? {
+                            if !limbo::accepts(handshake.protocol_version.0) {
+                                let disconnect = interceptor::gate::offline_login(address).await;
+                                s2c.lock().await.writer.as_mut().unwrap().write_all(&disconnect).await?;
+                                return Ok(());
+                            }
+
+                            limbo = Some(hello.username);
+                        }
+                    }
+                    Err(position) if queue_limbo && limbo::accepts(handshake.protocol_version.0) => {
+                        events::emit(EventKind::Queued { position }, address);
+                        limbo = Some(hello.username);
+                    }
+                    Err(position) => {
+                        let disconnect = interceptor::gate::queue_login(address, position).await;
+                        s2c.lock().await.writer.as_mut().unwrap().write_all(&disconnect).await?;
+                        return Ok(());
+                    }
+                }
+
+                protocol = Some(handshake.protocol_version.0);
+            }
         }
-        NextState::Login => {
-            make_gatekeeper!(c2s, LoginHello);
 
-            let mut c2s = c2s.lock().await;
-            let mut s2c = s2c.lock().await;
+        Ok::<_, anyhow::Error>(())
+    };
 
-            return tokio::select! {
-                c2s_res = passthrough(c2s.reader.take().unwrap(), c2s.writer.take().unwrap()) => c2s_res,
-                s2c_res = passthrough(s2c.reader.take().unwrap(), s2c.writer.take().unwrap()) => s2c_res,
-            };
+    tokio::select! {
+        result = gate => result?,
+        Some(reason) = kick.recv() => {
+            if logging_in {
+                let disconnect = make_bytes!(LoginDisconnectS2c { reason: Cow::Owned(Text::from(reason)) });
+                s2c.lock().await.writer.as_mut().unwrap().write_all(&disconnect).await?;
+            }
+            return Ok(());
         }
     }
 
-    return Ok(());
-}
+    let Some(protocol) = protocol else {
+        return Ok(());
+    };
 
-async fn passthrough(mut read: OwnedReadHalf, mut write: OwnedWriteHalf) -> anyhow::Result<()> {
-    let mut buf = Box::new([0u8; 8192]);
-    loop {
-        let bytes_read = read.read(buf.as_mut_slice()).await?;
-        let bytes = &mut buf[..bytes_read];
+    let mut c2s = c2s.lock().await;
+    let mut s2c = s2c.lock().await;
+    let profile = SESSIONS.lock().await.get(&address.to_string()).map(|v| v.profile.clone()).unwrap_or_default();
 
-        if bytes.is_empty() {
-            break Ok(());
-        }
+    if let Some(username) = captcha {
+        return captcha::challenge(c2s.reader.take().unwrap(), s2c.writer.take().unwrap(), address, &username, protocol, kick).await;
+    }
 
-        let bytes_write = write.write(bytes).await?;
+    // The place on the server is held for as long as the session lasts
+    let (client_reader, client_writer, server_reader, server_writer, state, _slot) = match limbo {
+        Some(username) => {
+            let client = limbo::Client { reader: c2s.reader.take().unwrap(), writer: s2c.writer.take().unwrap(), address, username, uuid, protocol, login: std::mem::take(&mut c2s.pending) };
+            let Some(transferred) = limbo::hold(client, listener, slot.take(), kick).await? else {
+                return Ok(());
+            };
 
-        unsafe {
-            TOTAL_DOWNLOAD.fetch_add(bytes_read as f64, std::sync::atomic::Ordering::Relaxed);
-            TOTAL_UPLOAD.fetch_add(bytes_write as f64, std::sync::atomic::Ordering::Relaxed);
+            let (server_reader, server_writer) = transferred.server.into_split();
+            (transferred.client_reader, transferred.client_writer, server_reader, server_writer, PipeState::transferred(protocol, transferred.compression, traffic.0, traffic.1, fingerprint, profile), Some(transferred.slot))
         }
+        None => (c2s.reader.take().unwrap(), s2c.writer.take().unwrap(), s2c.reader.take().unwrap(), c2s.writer.take().unwrap(), PipeState::new(protocol, traffic.0, traffic.1, fingerprint, profile), slot),
+    };
+
+    tokio::select! {
+        c2s_res = pipe(PacketDirection::C2S, client_reader, server_writer, &state, None) => c2s_res,
+        s2c_res = pipe(PacketDirection::S2C, server_reader, client_writer, &state, Some(kick)) => s2c_res,
+        _ = fingerprint::judge(address, state.fingerprint(), || state.readable()) => Ok(()),
+    }
+}
+
+/// Fails with what it was waiting for when `future` takes longer than
+/// `seconds`, 0 waits forever
+async fn deadline<T>(seconds: u64, what: &str, future: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+    if seconds == 0 {
+        return future.await;
     }
+
+    tokio::time::timeout(Duration::from_secs(seconds), future).await.map_err(|_| anyhow::anyhow!("Timed out waiting for {what}"))?
 }
 
-async fn accept_loop(proxy_address: SocketAddr, server_address: SocketAddr) {
-    let listener = if let Ok(listener) = TcpListener::bind(proxy_address).await {
-        info!("{}", colorizer!("c(on_red) VigilantGuard c(reset) is started at c(on_blue) {} ", proxy_address.to_string()));
+/// Accepts connections for one listener, the default one being rebound when
+/// `proxy.ip` or `proxy.port` are reloaded
+async fn accept_loop(source: listener::Source) {
+    let mut address = source.address();
+
+    let mut listener = if let Ok(listener) = TcpListener::bind(&address).await {
+        info!("{}", colorizer!("c(on_red) VigilantGuard c(reset) is started at c(on_blue) {} ", address));
         listener
     } else {
-        panic!("Failed to start the proxy server")
+        panic!("Failed to start the proxy server at {}", address)
     };
 
-    loop {
-        let addr;
-
-        let client_socket = if let Ok((socket, address)) = listener.accept().await {
-            info!("{}", colorizer!("[/c(dark_blue){address}c(reset)] Open connection"));
+    let stopped = shutdown::STOP_ACCEPTING.notified();
+    tokio::pin!(stopped);
+    stopped.as_mut().enable();
 
-            addr = address;
-
-            *CONNECTIONS.lock().await.entry(addr.clone().ip().to_string()).or_insert(0) += 1;
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut stopped => {
+                info!("{}", colorizer!("Stopped accepting new connections at c(on_blue) {} ", address));
+                return;
+            }
+            _ = LISTENER_REBIND.notified(), if source.rebinds() => {
+                match TcpListener::bind(source.address()).await {
+                    Ok(rebound) => {
+                        address = source.address();
+                        info!("{}", colorizer!("c(on_red) VigilantGuard c(reset) is now listening at c(on_blue) {} ", address));
+                        listener = rebound;
+                    }
+                    Err(err) => log::error!("{}", colorizer!("Failed to listen at c(on_blue) {} c(reset), keeping the old address: {}", source.address(), err.to_string())),
+                }
+                continue;
+            }
+        };
 
-            socket
-        } else {
-            panic!("Failed to accept a new connection")
+        let (mut client_socket, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // Like running out of file descriptors, which takes a moment to clear
+                log::error!("{}", colorizer!("Failed to accept a new connection at c(on_blue) {} c(reset): {}", address, err.to_string()));
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
         };
 
+        let settings = source.settings();
+        guardian::track_connection();
+
         RUNTIME.spawn(async move {
-            let server = TcpStream::connect(server_address).await;
-            match server {
-                Ok(server_socket) => {
-                    server_socket.set_nodelay(true).unwrap();
-                    SERVER_ALIVE.store(true, Ordering::Relaxed);
-
-                    if let Err(err) = proxy(client_socket, server_socket).await {
-                        log::error!("{}", colorizer!("[/c(dark_blue){}c(reset)] {}", addr.to_string(), err.to_string()));
-                    }
-                }
+            let addr = match listener::accept(&mut client_socket, &settings).await {
+                Ok(addr) => addr,
                 Err(err) => {
-                    if let ErrorKind::ConnectionRefused = err.kind() {
-                        SERVER_ALIVE.store(false, Ordering::Relaxed);
-                        let dummy_server = TcpListener::bind("127.0.0.1:0").await.unwrap();
-                        let dummy_socket = TcpStream::connect(dummy_server.local_addr().unwrap()).await.unwrap();
-                        if let Err(err) = proxy(client_socket, dummy_socket).await {
-                            log::error!("{}", colorizer!("[/c(dark_blue){}c(reset)] {}", addr.to_string(), err.to_string()));
-                        }
-                    }
+                    log::error!("{}", colorizer!("[/c(dark_blue){}c(reset)] {}", peer.to_string(), err.to_string()));
+                    return;
                 }
+            };
+
+            info!("{}", colorizer!("[/c(dark_blue){addr}c(reset)] Open connection"));
+
+            let key = guardian::ip_key(addr.ip());
+            let (kick_sender, mut kick) = mpsc::unbounded_channel();
+
+            *CONNECTIONS.lock().await.entry(key.clone()).or_insert(0) += 1;
+            let session = Session::new(addr, settings.clone(), kick_sender);
+            let traffic = (session.traffic.clone(), traffic::ip(&key));
+            let fingerprint = session.fingerprint.clone();
+            SESSIONS.lock().await.insert(addr.to_string(), session);
+            events::emit(EventKind::ConnectionOpened, addr);
+            let _closed = Closed { address: addr, peer, key };
+
+            if let Err(err) = proxy(client_socket, addr, &settings, &mut kick, traffic, fingerprint).await {
+                log::error!("{}", colorizer!("[/c(dark_blue){}c(reset)] {}", addr.to_string(), err.to_string()));
             }
+        });
+    }
+}
 
+/// Takes a connection out of the proxy's bookkeeping when dropped, so a
+/// session that panicked doesn't stay counted
+struct Closed {
+    address: SocketAddr,
+    peer: SocketAddr,
+    key: String,
+}
+
+impl Drop for Closed {
+    fn drop(&mut self) {
+        let (addr, peer, key) = (self.address, self.peer, std::mem::take(&mut self.key));
+
+        RUNTIME.spawn(async move {
             PLAYERS.lock().await.remove(&addr.to_string());
+            if let Some(session) = SESSIONS.lock().await.remove(&addr.to_string()) {
+                events::emit_session(EventKind::ConnectionClosed, &session);
+            }
+            listener::forget(peer);
             info!("{}", colorizer!("[/c(dark_blue){}c(reset)] Close connection", addr.to_string()));
-            CONNECTIONS.lock().await.entry(addr.ip().to_string()).and_modify(|v| *v -= 1);
+            let mut connections = CONNECTIONS.lock().await;
+            connections.entry(key.clone()).and_modify(|v| *v -= 1);
+            if connections.get(&key) == Some(&0) {
+                traffic::release(&key);
+            }
         });
     }
 }
 
+fn server_address() -> String {
+    let config = VIGILANT_CONFIG.load();
+    format!("{}:{}", config.server.ip, config.server.port)
+}
+
+/// Swaps in the config and lang files from disk, the current ones stay active
+/// if either of them fails to parse
+pub fn reload() -> anyhow::Result<()> {
+    match file::reload() {
+        Ok(previous) => {
+            info!("{}", colorizer!("c(bright_green)Reloaded the config and lang files"));
+
+            let config = VIGILANT_CONFIG.load();
+            if (&config.proxy.ip, config.proxy.port) != (&previous.proxy.ip, previous.proxy.port) {
+                LISTENER_REBIND.notify_one();
+            }
+
+            config_warn();
+            Ok(())
+        }
+        Err(err) => {
+            log::error!("{}", colorizer!("Failed to reload, keeping the current config: {}", format!("{err:#}")));
+            Err(err)
+        }
+    }
+}
+
+fn watch_files() {
+    thread::Builder::new()
+        .name("watcher".to_string())
+        .spawn(|| {
+            let modified = || [&cli::ARGS.config, &cli::ARGS.lang].map(|path| fs::metadata(path).and_then(|v| v.modified()).ok());
+            let mut last = modified();
+
+            loop {
+                thread::sleep(Duration::from_secs(2));
+
+                let current = modified();
+                if current != last && VIGILANT_CONFIG.load().watch_files {
+                    let _ = reload();
+                }
+                last = current;
+
+                script::refresh();
+            }
+        })
+        .unwrap();
+}
+
 fn config_warn() {
-    if !VIGILANT_CONFIG.proxy.forwarder.ip_forward {
+    let config = VIGILANT_CONFIG.load();
+
+    if !config.proxy.forwarder.ip_forward {
         log::warn!("{}", colorizer!("c(on_yellow) PLEASE TURN ON IP FORWARD!!! "));
         log::warn!("{}", colorizer!("c(on_yellow) UNLESS YOU KNOW WHAT YOU'RE DOING! "));
     }
+
+    if config.proxy.trusted_proxies.is_empty() && (config.proxy.proxy_protocol || config.listeners.iter().any(|v| v.proxy_protocol)) {
+        log::warn!("{}", colorizer!("The PROXY protocol is on but proxy.trusted_proxies is empty, every connection it is on for is refused"));
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let proxy_address = &format!("{}:{}", VIGILANT_CONFIG.proxy.ip, VIGILANT_CONFIG.proxy.port);
-    let server_address = &format!("{}:{}", VIGILANT_CONFIG.server.ip, VIGILANT_CONFIG.server.port);
+    if cli::ARGS.check_config {
+        std::process::exit(if file::check() { 0 } else { 1 });
+    }
+
+    // The logger depends on the config, so this is reported without it
+    file::init().context("Refusing to start, fix the file or delete it to generate the default one")?;
+    file::load_databases().context("Refusing to start")?;
 
     terminal::setup().expect("Failed to setup interactive terminal!");
 
     info!("{}", colorizer!("Loading VigilantGuard build ({}-{}-{})", env!("VERGEN_GIT_BRANCH"), env!("VERGEN_GIT_DESCRIBE"), env!("VERGEN_BUILD_DATE")));
 
-    let _ = &VIGILANT_LANG.server_offline_kick; // Preload the lang file to memory
+    let _ = plugin::PLUGINS.len(); // Load the plugins before accepting any connection
+    script::refresh();
 
     config_warn();
+    watch_files();
+
+    RUNTIME.spawn(guardian::attack_mode_watcher());
+    RUNTIME.spawn(shutdown::signals());
+    RUNTIME.spawn(metrics::serve());
+    RUNTIME.spawn(admin::serve());
+    RUNTIME.spawn(rcon::serve());
+    RUNTIME.spawn(status::refresher());
+    RUNTIME.spawn(vpn::evictor());
+
+    let mut sources = listener::Source::all().into_iter();
+    let default = sources.next().unwrap();
+
+    for source in sources {
+        RUNTIME.spawn(accept_loop(source));
+    }
 
-    let proxy_address = proxy_address.to_socket_addrs()?.next().unwrap();
-    let server_address = server_address.to_socket_addrs()?.next().unwrap();
+    accept_loop(default).await;
 
-    accept_loop(proxy_address, server_address).await;
+    // The graceful shutdown exits the process once the sessions are closed
+    std::future::pending::<()>().await;
     Ok(())
 }
@@ -0,0 +1,182 @@
+//! Prometheus metrics, served in the text format at `/metrics` on
+//! `metrics.ip`:`metrics.port` while `metrics.active`.
+//!
+//! The counters are fed by the event stream, so every event is counted
+//! whether or not `events.active` is on.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use log::{error, info};
+use once_cell::sync::Lazy;
+
+use crate::events::EventKind;
+use crate::file::VIGILANT_CONFIG;
+use crate::guardian::ATTACK_MODE;
+use crate::limits::{Stage, Violation};
+use crate::macros::coloriser;
+use crate::{queue, traffic, PLAYERS, SERVER_ALIVE, SESSIONS};
+
+pub static BYTES_C2S: AtomicU64 = AtomicU64::new(0);
+pub static BYTES_S2C: AtomicU64 = AtomicU64::new(0);
+pub static PACKETS_C2S: AtomicU64 = AtomicU64::new(0);
+pub static PACKETS_S2C: AtomicU64 = AtomicU64::new(0);
+
+pub static BACKEND_CONNECT: Latency = Latency::new();
+pub static VPN_LOOKUP: Latency = Latency::new();
+pub static QUEUE_WAIT: Latency = Latency::new();
+pub static VPN_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+pub static VPN_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+pub static VPN_FALLBACKS: AtomicU64 = AtomicU64::new(0);
+pub static EVENTS_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+static CONNECTIONS_TOTAL: AtomicU64 = AtomicU64::new(0);
+static STATUS_PINGS: AtomicU64 = AtomicU64::new(0);
+static LOGIN_ATTEMPTS: AtomicU64 = AtomicU64::new(0);
+static PLAY_SESSIONS: AtomicU64 = AtomicU64::new(0);
+static REJECTIONS: Lazy<Mutex<BTreeMap<&'static str, u64>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+static VIOLATIONS: Lazy<Mutex<BTreeMap<(&'static str, &'static str), u64>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// IPs `vigilant_ip_bytes_total` has series for
+const TOP_IPS: usize = 10;
+
+/// Running total of how long something took, exported as a summary
+pub struct Latency {
+    micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Latency {
+    const fn new() -> Self {
+        Self { micros: AtomicU64::new(0), count: AtomicU64::new(0) }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        self.micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn record(kind: &EventKind) {
+    let counter = match kind {
+        EventKind::ConnectionOpened => &CONNECTIONS_TOTAL,
+        EventKind::StatusPing => &STATUS_PINGS,
+        EventKind::LoginAttempt => &LOGIN_ATTEMPTS,
+        EventKind::LoginAllowed => &PLAY_SESSIONS,
+        EventKind::Rejection { reason, .. } => {
+            *REJECTIONS.lock().unwrap().entry(reason.as_str()).or_insert(0) += 1;
+            return;
+        }
+        _ => return,
+    };
+
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn violation(violation: Violation, stage: Stage) {
+    *VIOLATIONS.lock().unwrap().entry((violation.as_str(), stage.as_str())).or_insert(0) += 1;
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, f64)]) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{name}{labels} {value}");
+    }
+}
+
+fn summary(out: &mut String, name: &str, help: &str, latency: &Latency) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} summary");
+    let _ = writeln!(out, "{name}_sum {}", latency.micros.load(Ordering::Relaxed) as f64 / 1e+6);
+    let _ = writeln!(out, "{name}_count {}", latency.count.load(Ordering::Relaxed));
+}
+
+pub async fn render() -> String {
+    let counter = |v: &AtomicU64| v.load(Ordering::Relaxed) as f64;
+    let mut out = String::new();
+
+    metric(&mut out, "vigilant_connections_active", "gauge", "Open client connections", &[("", SESSIONS.lock().await.len() as f64)]);
+    metric(&mut out, "vigilant_players_online", "gauge", "Connections that got through the login", &[("", PLAYERS.lock().await.len() as f64)]);
+    metric(&mut out, "vigilant_connections_total", "counter", "Accepted client connections", &[("", counter(&CONNECTIONS_TOTAL))]);
+    metric(&mut out, "vigilant_status_pings_total", "counter", "Status requests", &[("", counter(&STATUS_PINGS))]);
+    metric(&mut out, "vigilant_login_attempts_total", "counter", "Login attempts", &[("", counter(&LOGIN_ATTEMPTS))]);
+    metric(&mut out, "vigilant_play_sessions_total", "counter", "Logins let through to the server", &[("", counter(&PLAY_SESSIONS))]);
+
+    let rejections = REJECTIONS.lock().unwrap().iter().map(|(reason, count)| (format!("{{reason=\"{reason}\"}}"), *count as f64)).collect::<Vec<_>>();
+    metric(&mut out, "vigilant_rejections_total", "counter", "Rejected logins by reason", &rejections.iter().map(|(labels, count)| (labels.as_str(), *count)).collect::<Vec<_>>());
+
+    let violations = VIOLATIONS.lock().unwrap().iter().map(|((kind, stage), count)| (format!("{{kind=\"{kind}\",stage=\"{stage}\"}}"), *count as f64)).collect::<Vec<_>>();
+    metric(&mut out, "vigilant_limit_violations_total", "counter", "Clients disconnected for going over the limits, by violation and stage", &violations.iter().map(|(labels, count)| (labels.as_str(), *count)).collect::<Vec<_>>());
+
+    metric(&mut out, "vigilant_backend_up", "gauge", "Whether the last connection to the backend succeeded", &[("", SERVER_ALIVE.load(Ordering::Relaxed) as u8 as f64)]);
+    summary(&mut out, "vigilant_backend_connect_seconds", "Time taken to connect to the backend", &BACKEND_CONNECT);
+
+    metric(&mut out, "vigilant_queue_playing", "gauge", "Players holding a place on the server", &[("", queue::playing() as f64)]);
+    metric(&mut out, "vigilant_queue_waiting", "gauge", "Players waiting in the join queue", &[("", queue::waiting() as f64)]);
+    summary(&mut out, "vigilant_queue_wait_seconds", "Time waited in the join queue by the players let in", &QUEUE_WAIT);
+
+    summary(&mut out, "vigilant_vpn_lookup_seconds", "Time taken by the VPN provider to answer", &VPN_LOOKUP);
+    metric(&mut out, "vigilant_vpn_cache_hits_total", "counter", "VPN checks answered from the IP filter databases or the verdict cache", &[("", counter(&VPN_CACHE_HITS))]);
+    metric(&mut out, "vigilant_vpn_cache_misses_total", "counter", "VPN checks that went to the provider", &[("", counter(&VPN_CACHE_MISSES))]);
+    metric(&mut out, "vigilant_vpn_fallbacks_total", "counter", "VPN checks given the fallback verdict because the provider failed or timed out", &[("", counter(&VPN_FALLBACKS))]);
+    metric(&mut out, "vigilant_events_dropped_total", "counter", "Events dropped because the event writer fell behind", &[("", counter(&EVENTS_DROPPED))]);
+
+    metric(&mut out, "vigilant_bytes_total", "counter", "Bytes forwarded by direction", &[("{direction=\"c2s\"}", counter(&BYTES_C2S)), ("{direction=\"s2c\"}", counter(&BYTES_S2C))]);
+    metric(&mut out, "vigilant_packets_total", "counter", "Packets forwarded by direction, not counting encrypted sessions", &[("{direction=\"c2s\"}", counter(&PACKETS_C2S)), ("{direction=\"s2c\"}", counter(&PACKETS_S2C))]);
+
+    // Only the busiest IPs, so the series don't grow with every IP that connects
+    let mut ips = traffic::ips();
+    ips.sort_by_key(|(_, traffic)| std::cmp::Reverse(traffic.bytes_c2s.load(Ordering::Relaxed) + traffic.bytes_s2c.load(Ordering::Relaxed)));
+    let ips = ips.iter().take(TOP_IPS).flat_map(|(ip, traffic)| [(format!("{{ip=\"{ip}\",direction=\"c2s\"}}"), counter(&traffic.bytes_c2s)), (format!("{{ip=\"{ip}\",direction=\"s2c\"}}"), counter(&traffic.bytes_s2c))]).collect::<Vec<_>>();
+    metric(&mut out, "vigilant_ip_bytes_total", "counter", "Bytes forwarded by the busiest IPs currently connected, by direction", &ips.iter().map(|(labels, bytes)| (labels.as_str(), *bytes)).collect::<Vec<_>>());
+    metric(&mut out, "vigilant_attack_mode", "gauge", "Whether attack mode is on", &[("", ATTACK_MODE.load(Ordering::Relaxed) as u8 as f64)]);
+
+    out
+}
+
+async fn handle(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if request.uri().path() != "/metrics" {
+        return Ok(Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap());
+    }
+
+    Ok(Response::builder().header("Content-Type", "text/plain; version=0.0.4").body(Body::from(render().await)).unwrap())
+}
+
+pub async fn serve() {
+    let config = VIGILANT_CONFIG.load();
+
+    if !config.metrics.active {
+        return;
+    }
+
+    let address = match format!("{}:{}", config.metrics.ip, config.metrics.port).parse::<SocketAddr>() {
+        Ok(address) => address,
+        Err(err) => {
+            error!("{}", coloriser!("Invalid metrics address: {}", err.to_string()));
+            return;
+        }
+    };
+
+    let server = match Server::try_bind(&address) {
+        Ok(server) => server,
+        Err(err) => {
+            error!("{}", coloriser!("Failed to serve the metrics at c(on_blue) {} c(reset): {}", address, err.to_string()));
+            return;
+        }
+    };
+
+    info!("{}", coloriser!("Serving metrics at c(on_blue) http://{}/metrics ", address));
+
+    if let Err(err) = server.serve(make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) })).await {
+        error!("{}", coloriser!("Metrics server stopped: {}", err.to_string()));
+    }
+}
@@ -6,3 +6,48 @@ pub enum PacketDirection {
     C2S,
     S2C,
 }
+
+/// Id of the play state disconnect packet, which moved around a lot between
+/// versions. `None` for versions the proxy doesn't know how to talk to, as
+/// 1.20.2 added a configuration state and 1.20.3 sends text as NBT.
+pub fn play_disconnect_id(protocol: i32) -> Option<i32> {
+    match protocol {
+        340 => Some(0x1A),       // 1.12.2
+        393..=404 => Some(0x1B), // 1.13 - 1.13.2
+        477..=498 => Some(0x1A), // 1.14 - 1.14.4
+        573..=578 => Some(0x1B), // 1.15 - 1.15.2
+        735 | 736 => Some(0x1A), // 1.16 - 1.16.1
+        751..=754 => Some(0x19), // 1.16.2 - 1.16.5
+        755..=758 => Some(0x1A), // 1.17 - 1.18.2
+        759 => Some(0x17),       // 1.19
+        760 => Some(0x19),       // 1.19.1 - 1.19.2
+        761 => Some(0x17),       // 1.19.3
+        762 | 763 => Some(0x1A), // 1.19.4 - 1.20.1
+        _ => None,
+    }
+}
+
+/// Ids of the play packets the fingerprinting looks at
+pub struct PlayIds {
+    pub client_information: i32,
+    pub plugin_message: i32,
+    pub keep_alive_c2s: i32,
+    pub position: i32,
+    pub keep_alive_s2c: i32,
+}
+
+/// Same as [`play_disconnect_id`], for the versions that were checked. The
+/// position and rotation packet always comes right after `position`.
+pub fn play_ids(protocol: i32) -> Option<PlayIds> {
+    let (client_information, plugin_message, keep_alive_c2s, position, keep_alive_s2c) = match protocol {
+        340 => (0x04, 0x09, 0x0B, 0x0D, 0x1F),       // 1.12.2
+        755..=758 => (0x05, 0x0A, 0x0F, 0x11, 0x21), // 1.17 - 1.18.2
+        759 => (0x07, 0x0C, 0x11, 0x13, 0x1E),       // 1.19
+        760 => (0x08, 0x0D, 0x12, 0x14, 0x20),       // 1.19.1 - 1.19.2
+        761 => (0x07, 0x0C, 0x11, 0x13, 0x1F),       // 1.19.3
+        762 | 763 => (0x08, 0x0D, 0x12, 0x14, 0x23), // 1.19.4 - 1.20.1
+        _ => return None,
+    };
+
+    Some(PlayIds { client_information, plugin_message, keep_alive_c2s, position, keep_alive_s2c })
+}
@@ -1,3 +1,7 @@
+use valence_nbt::Compound;
+use valence_protocol::text::Text;
+use valence_protocol::uuid::Uuid;
+use valence_protocol::var_int::VarInt;
 use valence_protocol::{Decode, Encode, Packet};
 
 #[derive(Clone, Debug, Encode, Decode, Packet)]
@@ -11,3 +15,132 @@ pub struct QueryResponse {
 pub struct QueryPong {
     pub payload: u64,
 }
+
+#[derive(Clone, Debug, Encode, Decode, Packet)]
+#[packet_id = 0x02]
+pub struct LoginSuccess {
+    pub uuid: Uuid,
+    pub username: String,
+    pub properties: Vec<Property>,
+}
+
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct Property {
+    pub name: String,
+    pub value: String,
+    pub signature: Option<String>,
+}
+
+// The play packets below are the ones of 1.19.4 - 1.20.1, the versions the
+// limbo speaks
+
+#[derive(Clone, Debug, Encode, Decode, Packet)]
+#[packet_id = 0x0E]
+pub struct ClearTitles {
+    pub reset: bool,
+}
+
+#[derive(Clone, Debug, Encode, Decode, Packet)]
+#[packet_id = 0x1A]
+pub struct Disconnect {
+    pub reason: Text,
+}
+
+#[derive(Clone, Debug, Encode, Decode, Packet)]
+#[packet_id = 0x23]
+pub struct KeepAlive {
+    pub id: i64,
+}
+
+#[derive(Clone, Debug, Encode, Decode, Packet)]
+#[packet_id = 0x28]
+pub struct JoinGame {
+    pub entity_id: i32,
+    pub hardcore: bool,
+    pub game_mode: u8,
+    pub previous_game_mode: i8,
+    pub dimensions: Vec<String>,
+    pub registry_codec: Compound,
+    pub dimension_type: String,
+    pub dimension: String,
+    pub hashed_seed: i64,
+    pub max_players: VarInt,
+    pub view_distance: VarInt,
+    pub simulation_distance: VarInt,
+    pub reduced_debug_info: bool,
+    pub respawn_screen: bool,
+    pub debug: bool,
+    pub flat: bool,
+    pub death_location: Option<DeathLocation>,
+    /// Since 1.20
+    pub portal_cooldown: Trailing<VarInt>,
+}
+
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct DeathLocation {
+    pub dimension: String,
+    pub position: i64,
+}
+
+#[derive(Clone, Debug, Encode, Decode, Packet)]
+#[packet_id = 0x3C]
+pub struct PlayerPosition {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub flags: u8,
+    pub teleport_id: VarInt,
+}
+
+#[derive(Clone, Debug, Encode, Decode, Packet)]
+#[packet_id = 0x46]
+pub struct ActionBar {
+    pub text: Text,
+}
+
+#[derive(Clone, Debug, Encode, Decode, Packet)]
+#[packet_id = 0x50]
+pub struct SpawnPosition {
+    /// Packed as x in the top 26 bits, z in the next 26 and y in the last 12
+    pub position: i64,
+    pub angle: f32,
+}
+
+#[derive(Clone, Debug, Encode, Decode, Packet)]
+#[packet_id = 0x5F]
+pub struct Title {
+    pub text: Text,
+}
+
+#[derive(Clone, Debug, Encode, Decode, Packet)]
+#[packet_id = 0x60]
+pub struct TitleTimes {
+    pub fade_in: i32,
+    pub stay: i32,
+    pub fade_out: i32,
+}
+
+/// A field newer versions added at the end of a packet, written only when
+/// it is `Some`
+#[derive(Clone, Debug)]
+pub struct Trailing<T>(pub Option<T>);
+
+impl<T: Encode> Encode for Trailing<T> {
+    fn encode(&self, w: impl std::io::Write) -> anyhow::Result<()> {
+        match &self.0 {
+            Some(value) => value.encode(w),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a, T: Decode<'a>> Decode<'a> for Trailing<T> {
+    fn decode(r: &mut &'a [u8]) -> anyhow::Result<Self> {
+        match r.is_empty() {
+            true => Ok(Self(None)),
+            false => Ok(Self(Some(T::decode(r)?))),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! WebAssembly plugins that can take part in the login decision next to the
+//! built-in guardian filters.
+//!
+//! A plugin is a `.wasm` module in the plugin directory that exports its
+//! `memory`, a `vg_alloc(len: i32) -> i32` allocator and any of the hooks
+//! below. Payloads are passed as JSON written into memory returned by
+//! `vg_alloc`, hooks answer with a packed `ptr << 32 | len` (or `0`).
+//!
+//! - `vg_init(ptr, len) -> i64` receives the plugin's `config` table once
+//! - `vg_on_login(ptr, len) -> i64` receives a [`LoginContext`] and answers
+//!   with a [`PluginVerdict`]
+//!
+//! The host exposes `log`, `connection_count` and `ip_pinged` under the
+//! `vigilant` import module. The last two answer as of the start of the call,
+//! and with nothing yet during `vg_init`.
+//!
+//! A plugin's memory can't grow past `plugins.memory`, and any pointer it hands
+//! back has to stay within it.
+
+mod wasm;
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{fs, thread};
+
+use anyhow::Context;
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use wasmtime::{Config, Engine};
+
+use self::wasm::{Snapshot, WasmPlugin};
+use crate::file::VIGILANT_CONFIG;
+use crate::macros::coloriser;
+use crate::session::Session;
+use crate::{CONNECTIONS, IP_CACHE};
+
+pub static PLUGINS: Lazy<Vec<WasmPlugin>> = Lazy::new(|| {
+    load().unwrap_or_else(|err| {
+        error!("{}", coloriser!("Failed to load the plugins: {}", format!("{err:#}")));
+        Vec::new()
+    })
+});
+
+static ENGINE: Lazy<Engine> = Lazy::new(|| {
+    let mut config = Config::new();
+    config.epoch_interruption(true);
+
+    let engine = Engine::new(&config).expect("Failed to create the plugin engine");
+
+    // One epoch is one millisecond, which is the unit of `plugins.timeout`
+    let ticker = engine.clone();
+    thread::Builder::new()
+        .name("plugin-epoch".to_string())
+        .spawn(move || loop {
+            thread::sleep(Duration::from_millis(1));
+            ticker.increment_epoch();
+        })
+        .unwrap();
+
+    engine
+});
+
+#[derive(Serialize)]
+pub struct LoginContext {
+    pub ip: String,
+    pub session: Session,
+    pub username: String,
+    pub profile_id: Option<String>,
+    pub connections: usize,
+    pub pinged: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum PluginVerdict {
+    Allow,
+    Kick { reason: String },
+    Modify { username: Option<String> },
+}
+
+fn load() -> anyhow::Result<Vec<WasmPlugin>> {
+    let config = VIGILANT_CONFIG.load();
+    let mut plugins = Vec::new();
+
+    if !config.plugins.active {
+        return Ok(plugins);
+    }
+
+    let entries = match fs::read_dir(&config.plugins.directory) {
+        Ok(entries) => entries,
+        Err(_) => {
+            fs::create_dir_all(&config.plugins.directory).with_context(|| format!("Failed to create {}", config.plugins.directory))?;
+            return Ok(plugins);
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+
+        if path.extension().is_none_or(|v| v != "wasm") {
+            continue;
+        }
+
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+
+        match config.plugins.list.get(&name) {
+            Some(plugin) if plugin.active => {}
+            _ => {
+                info!("{}", coloriser!("Skipping plugin c(dark_purple){}c(reset), it is not enabled in the config", name));
+                continue;
+            }
+        }
+
+        match init(&name, &path) {
+            Ok(plugin) => {
+                info!("{}", coloriser!("Loaded plugin c(dark_purple){}", name));
+                plugins.push(plugin);
+            }
+            Err(err) => error!("{}", coloriser!("Failed to load plugin c(dark_purple){}c(reset): {}", name, err.to_string())),
+        }
+    }
+
+    Ok(plugins)
+}
+
+fn init(name: &str, path: &Path) -> anyhow::Result<WasmPlugin> {
+    let timeout = VIGILANT_CONFIG.load().plugins.timeout;
+    let plugin = WasmPlugin::load(&ENGINE, name, path, timeout, VIGILANT_CONFIG.load().plugins.memory * 1024 * 1024)?;
+
+    let config = serde_json::to_string(&VIGILANT_CONFIG.load().plugins.list[name].config)?;
+    plugin.call("vg_init", &config, timeout, Arc::default())?;
+
+    Ok(plugin)
+}
+
+/// Runs every loaded plugin's `vg_on_login` hook in order, stopping at the
+/// first one that does not allow the login. A plugin that errors or runs past
+/// `plugins.timeout` is logged and treated as allowing it.
+pub async fn on_login(context: LoginContext) -> PluginVerdict {
+    if PLUGINS.is_empty() {
+        return PluginVerdict::Allow;
+    }
+
+    let payload = serde_json::to_string(&context).unwrap();
+    let timeout = VIGILANT_CONFIG.load().plugins.timeout;
+    let connections = CONNECTIONS.lock().await.clone();
+    let now = Instant::now();
+    let pinged = IP_CACHE.lock().await.iter().filter(|(_, expires)| **expires > now).map(|(ip, _)| ip.clone()).collect::<HashSet<_>>();
+    let snapshot = Arc::new(Snapshot { connections, pinged });
+
+    for index in 0..PLUGINS.len() {
+        let payload = payload.clone();
+        let snapshot = snapshot.clone();
+        let call = tokio::task::spawn_blocking(move || PLUGINS[index].call("vg_on_login", &payload, timeout, snapshot));
+        let name = &PLUGINS[index].name;
+
+        let verdict = match tokio::time::timeout(Duration::from_millis(timeout), call).await {
+            Ok(Ok(Ok(Some(answer)))) => match serde_json::from_str::<PluginVerdict>(&answer) {
+                Ok(verdict) => verdict,
+                Err(err) => {
+                    warn!("{}", coloriser!("[c(dark_purple){}c(reset)] Invalid verdict: {}", name, err.to_string()));
+                    PluginVerdict::Allow
+                }
+            },
+            Ok(Ok(Ok(None))) => PluginVerdict::Allow,
+            Ok(Ok(Err(err))) => {
+                warn!("{}", coloriser!("[c(dark_purple){}c(reset)] {}", name, err.to_string()));
+                PluginVerdict::Allow
+            }
+            Ok(Err(err)) => {
+                error!("{}", coloriser!("[c(dark_purple){}c(reset)] {}", name, err.to_string()));
+                PluginVerdict::Allow
+            }
+            Err(_) => {
+                warn!("{}", coloriser!("[c(dark_purple){}c(reset)] Timed out after {}ms", name, timeout));
+                PluginVerdict::Allow
+            }
+        };
+
+        if let PluginVerdict::Allow = verdict {
+            continue;
+        }
+
+        return verdict;
+    }
+
+    PluginVerdict::Allow
+}
@@ -0,0 +1,123 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use log::info;
+use wasmtime::{Caller, Engine, Instance, Linker, Memory, Module, Store, StoreContext, StoreLimits, StoreLimitsBuilder};
+
+use crate::macros::coloriser;
+
+/// A single WebAssembly plugin, instantiated once and kept alive for the
+/// lifetime of the proxy so it can hold its own state between calls.
+pub struct WasmPlugin {
+    pub name: String,
+    instance: Mutex<(Store<Host>, Instance)>,
+}
+
+/// What the host functions answer from, taken before a call as they can't
+/// wait for the proxy's own locks
+#[derive(Default)]
+pub struct Snapshot {
+    pub connections: HashMap<String, usize>,
+    pub pinged: HashSet<String>,
+}
+
+pub struct Host {
+    name: String,
+    snapshot: Arc<Snapshot>,
+    limits: StoreLimits,
+}
+
+impl WasmPlugin {
+    pub fn load(engine: &Engine, name: &str, path: &Path, deadline: u64, memory: usize) -> anyhow::Result<Self> {
+        let module = Module::from_file(engine, path)?;
+        let mut linker = Linker::new(engine);
+
+        linker.func_wrap("vigilant", "log", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+            let message = read_string(&mut caller, ptr, len).unwrap_or_default();
+            info!("{}", coloriser!("[c(dark_purple){}c(reset)] {}", caller.data().name, message));
+        })?;
+
+        linker.func_wrap("vigilant", "connection_count", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> i32 {
+            let ip = read_string(&mut caller, ptr, len).unwrap_or_default();
+            caller.data().snapshot.connections.get(&ip).copied().unwrap_or(0) as i32
+        })?;
+
+        linker.func_wrap("vigilant", "ip_pinged", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> i32 {
+            let ip = read_string(&mut caller, ptr, len).unwrap_or_default();
+            caller.data().snapshot.pinged.contains(&ip) as i32
+        })?;
+
+        let limits = StoreLimitsBuilder::new().memory_size(memory).build();
+        let mut store = Store::new(engine, Host { name: name.to_string(), snapshot: Arc::default(), limits });
+        store.limiter(|host| &mut host.limits);
+        store.set_epoch_deadline(deadline);
+
+        let instance = linker.instantiate(&mut store, &module)?;
+
+        Ok(Self { name: name.to_string(), instance: Mutex::new((store, instance)) })
+    }
+
+    /// Calls an exported hook with a JSON payload, returning the JSON the
+    /// plugin answered with, or `None` when it returned nothing.
+    ///
+    /// A hook has the signature `(ptr: i32, len: i32) -> i64`, where the result
+    /// packs the pointer of the answer in the high 32 bits and its length in
+    /// the low 32 bits.
+    ///
+    /// Fails right away while a call that timed out is still running, until
+    /// the epoch deadline interrupts it.
+    pub fn call(&self, hook: &str, payload: &str, deadline: u64, snapshot: Arc<Snapshot>) -> anyhow::Result<Option<String>> {
+        let mut lock = self.instance.try_lock().map_err(|_| anyhow!("still busy with a call that timed out"))?;
+        let (store, instance) = &mut *lock;
+        store.data_mut().snapshot = snapshot;
+
+        let Some(func) = instance.get_func(&mut *store, hook) else {
+            return Ok(None);
+        };
+        let func = func.typed::<(i32, i32), i64>(&*store)?;
+
+        store.set_epoch_deadline(deadline);
+
+        let memory = memory(store, instance)?;
+        let ptr = write_payload(store, instance, &memory, payload)?;
+
+        let result = func.call(&mut *store, (ptr, payload.len() as i32))?;
+
+        if result == 0 {
+            return Ok(None);
+        }
+
+        let (ptr, len) = ((result >> 32) as u32 as usize, result as u32 as usize);
+        let answer = slice(&memory, &*store, ptr, len).ok_or(anyhow!("answered with memory out of bounds"))?;
+
+        Ok(Some(String::from_utf8(answer.to_vec())?))
+    }
+}
+
+fn memory(store: &mut Store<Host>, instance: &Instance) -> anyhow::Result<Memory> {
+    instance.get_memory(&mut *store, "memory").ok_or(anyhow!("plugin does not export its memory"))
+}
+
+fn write_payload(store: &mut Store<Host>, instance: &Instance, memory: &Memory, payload: &str) -> anyhow::Result<i32> {
+    let alloc = instance.get_typed_func::<i32, i32>(&mut *store, "vg_alloc")?;
+    let ptr = alloc.call(&mut *store, payload.len() as i32)?;
+
+    memory.write(&mut *store, ptr as usize, payload.as_bytes())?;
+
+    Ok(ptr)
+}
+
+fn read_string(caller: &mut Caller<'_, Host>, ptr: i32, len: i32) -> Option<String> {
+    let memory = caller.get_export("memory")?.into_memory()?;
+    let bytes = slice(&memory, &*caller, usize::try_from(ptr).ok()?, usize::try_from(len).ok()?)?;
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+/// The plugin's memory at `ptr..ptr + len`, or `None` when any of it is
+/// outside of it, checked before anything is copied as both come from the
+/// plugin
+fn slice<'a>(memory: &Memory, store: impl Into<StoreContext<'a, Host>>, ptr: usize, len: usize) -> Option<&'a [u8]> {
+    memory.data(store).get(ptr..ptr.checked_add(len)?)
+}
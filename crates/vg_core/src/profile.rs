@@ -0,0 +1,98 @@
+//! Guardian profiles, named sets of `[guardian.profiles.<name>]` filters a
+//! player is held to in place of the ones of `[guardian]`. The profile is
+//! picked at the handshake, from the listener the player came through, else
+//! from the hostname it joins with in `guardian.hostnames`, so a hostname
+//! can't loosen the profile of a listener. While attack mode is on,
+//! `guardian.attack_profile` takes over for everyone.
+//!
+//! `guardian.limits` stays global, frames are held to it from the first byte,
+//! before the handshake says which profile applies.
+
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+
+use crate::file::config_file::{BandwidthLimiter, GuardianConfig, GuardianProfile, ListenerConfig};
+use crate::file::VIGILANT_CONFIG;
+use crate::guardian::ATTACK_MODE;
+
+/// Name of the profile of a player, empty for `[guardian]` itself
+pub fn select(listener: &ListenerConfig, hostname: &str) -> String {
+    if !listener.profile.is_empty() {
+        return listener.profile.clone();
+    }
+
+    by_hostname(&VIGILANT_CONFIG.load().guardian.hostnames, hostname)
+}
+
+fn by_hostname(hostnames: &HashMap<String, String>, hostname: &str) -> String {
+    // Forge and other proxies append their own data after a null character
+    let hostname = hostname.split('\0').next().unwrap_or_default().trim_end_matches('.');
+
+    hostnames.iter().find(|(host, _)| host.eq_ignore_ascii_case(hostname)).map_or(String::new(), |(_, profile)| profile.clone())
+}
+
+/// The profile `name` stands for right now
+fn current<'a>(guardian: &'a GuardianConfig, name: &'a str) -> Option<&'a GuardianProfile> {
+    match ATTACK_MODE.load(Ordering::Relaxed) && !guardian.attack_profile.is_empty() {
+        true => guardian.profiles.get(&guardian.attack_profile),
+        false => guardian.profiles.get(name),
+    }
+}
+
+/// The filters of the profile `name` as they apply right now
+pub fn resolve(name: &str) -> GuardianProfile {
+    resolve_in(&VIGILANT_CONFIG.load().guardian, name)
+}
+
+fn resolve_in(guardian: &GuardianConfig, name: &str) -> GuardianProfile {
+    match current(guardian, name) {
+        Some(profile) => profile.clone(),
+        None => GuardianProfile { ping_protection: guardian.ping_protection.clone(), ip_connection_limit: guardian.ip_connection_limit.clone(), rate_limit: guardian.rate_limit.clone(), vpn_filter: guardian.vpn_filter.clone(), bandwidth: guardian.bandwidth.clone(), whitelist: guardian.whitelist.clone() },
+    }
+}
+
+/// Only the bandwidth caps of [`resolve`], for the pipe to look up as often
+/// as it reads
+pub fn bandwidth(name: &str) -> BandwidthLimiter {
+    let config = VIGILANT_CONFIG.load();
+    let guardian = &config.guardian;
+
+    current(guardian, name).map_or(&guardian.bandwidth, |v| &v.bandwidth).clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hostnames() -> HashMap<String, String> {
+        HashMap::from([("play.example.com".to_string(), "lenient".to_string())])
+    }
+
+    #[test]
+    fn matches_hostnames_loosely() {
+        assert_eq!(by_hostname(&hostnames(), "play.example.com"), "lenient");
+        assert_eq!(by_hostname(&hostnames(), "PLAY.Example.com."), "lenient");
+        assert_eq!(by_hostname(&hostnames(), "play.example.com\0FML3\0"), "lenient");
+        assert_eq!(by_hostname(&hostnames(), "other.example.com"), "");
+    }
+
+    #[test]
+    fn profile_limits_override_the_global_ones() {
+        let mut guardian = crate::file::config_file::Config::default().guardian;
+        let mut strict = resolve_in(&guardian, "");
+        strict.rate_limit.logins = 1;
+        strict.ip_connection_limit.limit = 1;
+        guardian.profiles.insert("strict".to_string(), strict);
+
+        assert_eq!(resolve_in(&guardian, "strict").rate_limit.logins, 1);
+        assert_eq!(resolve_in(&guardian, "strict").ip_connection_limit.limit, 1);
+        assert_eq!(resolve_in(&guardian, "").rate_limit.logins, guardian.rate_limit.logins);
+        assert_eq!(resolve_in(&guardian, "missing").ip_connection_limit.limit, guardian.ip_connection_limit.limit);
+    }
+
+    #[test]
+    fn listener_profile_wins() {
+        let strict = ListenerConfig { profile: "strict".to_string(), ..Default::default() };
+        assert_eq!(select(&strict, "play.example.com"), "strict");
+    }
+}
@@ -0,0 +1,160 @@
+//! Join queue for when the server can only take `queue.capacity` players at
+//! once. A login past that waits its turn, in the limbo when its protocol can
+//! be held there and `queue.limbo` is on, otherwise it is kicked with its
+//! position and keeps it for `queue.keep` seconds to reconnect.
+//!
+//! Higher `queue.priority` tiers go ahead of lower ones, and players of the
+//! same tier go in the order they came. Tiers are keyed by the UUID the client
+//! claims at login, before anything is authenticated: an online mode server
+//! still turns away a client that isn't who it claims once it is let in, but
+//! on an offline mode server anyone can take a listed UUID's tier.
+//!
+//! Only the players connected right now hold back the ones behind them, a
+//! kicked player keeps its place but is passed over until it reconnects.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::info;
+use once_cell::sync::Lazy;
+use valence_protocol::uuid::Uuid;
+
+use crate::file::VIGILANT_CONFIG;
+use crate::macros::coloriser;
+use crate::metrics;
+
+static WAITING: Lazy<Mutex<Vec<Waiting>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static PLAYING: AtomicUsize = AtomicUsize::new(0);
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// How long after it was last seen a waiting player counts as connected, the
+/// limbo keeps its players seen every second
+const PRESENT: Duration = Duration::from_secs(3);
+
+pub struct Waiting {
+    pub username: String,
+    pub tier: u32,
+    since: Instant,
+    /// Last time the player was connected, a kicked player is forgotten
+    /// `queue.keep` seconds after it
+    seen: Instant,
+}
+
+impl Waiting {
+    pub fn waited(&self) -> Duration {
+        self.since.elapsed()
+    }
+
+    pub fn present(&self) -> bool {
+        self.seen.elapsed() < PRESENT
+    }
+}
+
+/// A place on the server, freed when dropped
+pub struct Slot(());
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        PLAYING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn slot() -> Slot {
+    PLAYING.fetch_add(1, Ordering::Relaxed);
+    Slot(())
+}
+
+/// The `queue.priority` tier of `uuid`
+fn tier(uuid: Uuid) -> u32 {
+    VIGILANT_CONFIG.load().queue.priority.iter().find(|(key, _)| key.parse::<Uuid>().ok() == Some(uuid)).map_or(0, |(_, tier)| *tier)
+}
+
+/// Lets `username` in when there is room and nobody connected is ahead of it,
+/// otherwise queues it and returns its position, starting at 1. Called again
+/// by a waiting player to keep its place.
+pub fn admit(username: &str, uuid: Uuid) -> Result<Slot, usize> {
+    let config = VIGILANT_CONFIG.load();
+    let queue = &config.queue;
+
+    if !queue.active {
+        return Ok(slot());
+    }
+
+    let mut waiting = WAITING.lock().unwrap();
+    let keep = Duration::from_secs(queue.keep.max(5));
+    waiting.retain(|v| v.seen.elapsed() < keep);
+
+    let index = match waiting.iter().position(|v| v.username == username) {
+        Some(index) => {
+            waiting[index].seen = Instant::now();
+            index
+        }
+        None => {
+            let tier = tier(uuid);
+            let index = waiting.iter().position(|v| v.tier < tier).unwrap_or(waiting.len());
+            waiting.insert(index, Waiting { username: username.to_string(), tier, since: Instant::now(), seen: Instant::now() });
+            index
+        }
+    };
+
+    let ahead = waiting[..index].iter().filter(|v| v.present()).count();
+
+    if PAUSED.load(Ordering::Relaxed) || ahead >= queue.capacity.saturating_sub(PLAYING.load(Ordering::Relaxed)) {
+        return Err(index + 1);
+    }
+
+    metrics::QUEUE_WAIT.observe(waiting.remove(index).waited());
+    Ok(slot())
+}
+
+/// Gives up the place of a player that left while waiting
+pub fn leave(username: &str) {
+    WAITING.lock().unwrap().retain(|v| v.username != username);
+}
+
+pub fn playing() -> usize {
+    PLAYING.load(Ordering::Relaxed)
+}
+
+pub fn waiting() -> usize {
+    WAITING.lock().unwrap().len()
+}
+
+/// Runs `f` over the players waiting, in the order they get in
+pub fn with_waiting<T>(f: impl FnOnce(&[Waiting]) -> T) -> T {
+    f(&WAITING.lock().unwrap())
+}
+
+/// Moves a waiting player to the front, in the tier of whoever was there
+pub fn promote(username: &str) -> bool {
+    let mut waiting = WAITING.lock().unwrap();
+
+    let Some(index) = waiting.iter().position(|v| v.username == username) else {
+        return false;
+    };
+
+    let mut entry = waiting.remove(index);
+    entry.tier = waiting.first().map_or(entry.tier, |v| v.tier.max(entry.tier));
+    waiting.insert(0, entry);
+
+    true
+}
+
+pub fn remove(username: &str) -> bool {
+    let mut waiting = WAITING.lock().unwrap();
+    let before = waiting.len();
+    waiting.retain(|v| v.username != username);
+
+    waiting.len() != before
+}
+
+/// Stops letting anyone in from the queue, the players keep their places
+pub fn pause(paused: bool) {
+    PAUSED.store(paused, Ordering::Relaxed);
+    info!("{}", coloriser!("{}", if paused { "Paused the queue" } else { "Resumed the queue" }));
+}
+
+pub fn paused() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,223 @@
+//! Remote console over the Source RCON protocol, as spoken by Minecraft's own
+//! `enable-rcon`. Served on `rcon.ip`:`rcon.port` while `rcon.active`, a
+//! client logs in with `rcon.password` and then runs the console commands.
+//!
+//! A packet is `length: i32, id: i32, type: i32, body, 0, 0` in little
+//! endian, where `length` counts everything after itself.
+//!
+//! Every wrong password is answered a second late, and an IP that gets it
+//! wrong `LOCKOUT_AFTER` times in a row is refused for `LOCKOUT`.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::file::VIGILANT_CONFIG;
+use crate::logger::strip_ansi;
+use crate::macros::coloriser;
+use crate::{admin, command, RUNTIME};
+
+const RESPONSE: i32 = 0;
+const COMMAND: i32 = 2;
+const AUTH_RESPONSE: i32 = 2;
+const AUTH: i32 = 3;
+
+/// Longest body the vanilla client accepts in one response packet, longer
+/// output is split over several packets with the same id
+const MAX_BODY: usize = 4096;
+
+const FAILURE_DELAY: Duration = Duration::from_secs(1);
+const LOCKOUT_AFTER: u32 = 5;
+const LOCKOUT: Duration = Duration::from_secs(300);
+
+/// Wrong passwords in a row per IP, with when the last one came in
+static FAILURES: Lazy<Mutex<HashMap<IpAddr, (u32, Instant)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn locked_out(ip: IpAddr) -> bool {
+    let mut failures = FAILURES.lock().unwrap();
+    failures.retain(|_, (_, last)| last.elapsed() < LOCKOUT);
+    failures.get(&ip).is_some_and(|(count, _)| *count >= LOCKOUT_AFTER)
+}
+
+fn record_auth(ip: IpAddr, success: bool) {
+    let mut failures = FAILURES.lock().unwrap();
+
+    if success {
+        failures.remove(&ip);
+    } else {
+        let entry = failures.entry(ip).or_insert((0, Instant::now()));
+        *entry = (entry.0 + 1, Instant::now());
+    }
+}
+
+/// Splits `text` into pieces of at most `max` bytes without cutting a
+/// character in half
+fn chunks(text: &str, max: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = text;
+
+    while !rest.is_empty() {
+        let mut end = max.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        let (chunk, remaining) = rest.split_at(end);
+        chunks.push(chunk);
+        rest = remaining;
+    }
+
+    chunks
+}
+
+async fn read_packet(stream: &mut TcpStream) -> anyhow::Result<Option<(i32, i32, String)>> {
+    let len = match stream.read_i32_le().await {
+        Ok(len) => len,
+        Err(_) => return Ok(None),
+    };
+
+    anyhow::ensure!((10..=MAX_BODY as i32 + 10).contains(&len), "Invalid packet length {len}");
+
+    let mut buf = vec![0u8; len as usize];
+    stream.read_exact(&mut buf).await?;
+
+    let id = i32::from_le_bytes(buf[0..4].try_into()?);
+    let kind = i32::from_le_bytes(buf[4..8].try_into()?);
+    let body = String::from_utf8_lossy(&buf[8..buf.len() - 2]).to_string();
+
+    Ok(Some((id, kind, body)))
+}
+
+async fn write_packet(stream: &mut TcpStream, id: i32, kind: i32, body: &[u8]) -> anyhow::Result<()> {
+    let mut buf = Vec::with_capacity(body.len() + 14);
+    buf.extend_from_slice(&(body.len() as i32 + 10).to_le_bytes());
+    buf.extend_from_slice(&id.to_le_bytes());
+    buf.extend_from_slice(&kind.to_le_bytes());
+    buf.extend_from_slice(body);
+    buf.extend_from_slice(&[0, 0]);
+
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+async fn handle(mut stream: TcpStream, address: SocketAddr) -> anyhow::Result<()> {
+    let mut authenticated = false;
+
+    while let Some((id, kind, body)) = read_packet(&mut stream).await? {
+        match kind {
+            AUTH => {
+                if locked_out(address.ip()) {
+                    warn!("{}", coloriser!("[rcon/c(dark_blue){}c(reset)] Too many wrong passwords, closing", address));
+                    write_packet(&mut stream, -1, AUTH_RESPONSE, b"").await?;
+                    return Ok(());
+                }
+
+                authenticated = admin::secret_eq(&body, &VIGILANT_CONFIG.load().rcon.password);
+                record_auth(address.ip(), authenticated);
+
+                if authenticated {
+                    info!("{}", coloriser!("[rcon/c(dark_blue){}c(reset)] Logged in", address));
+                } else {
+                    warn!("{}", coloriser!("[rcon/c(dark_blue){}c(reset)] Wrong password", address));
+                    tokio::time::sleep(FAILURE_DELAY).await;
+                }
+
+                write_packet(&mut stream, if authenticated { id } else { -1 }, AUTH_RESPONSE, b"").await?;
+            }
+            COMMAND if authenticated => {
+                info!("{}", coloriser!("[rcon/c(dark_blue){}c(reset)] {}", address, body));
+
+                let output = strip_ansi(&command::execute(&body, VIGILANT_CONFIG.load().rcon.permission).await);
+
+                if output.is_empty() {
+                    write_packet(&mut stream, id, RESPONSE, b"").await?;
+                }
+                for chunk in chunks(&output, MAX_BODY) {
+                    write_packet(&mut stream, id, RESPONSE, chunk.as_bytes()).await?;
+                }
+            }
+            COMMAND => write_packet(&mut stream, -1, AUTH_RESPONSE, b"").await?,
+            _ => write_packet(&mut stream, id, RESPONSE, format!("Unknown request {kind}").as_bytes()).await?,
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn serve() {
+    let config = VIGILANT_CONFIG.load();
+
+    if !config.rcon.active {
+        return;
+    }
+
+    if config.rcon.password.is_empty() {
+        error!("{}", coloriser!("Not starting RCON, c(dark_purple)rcon.passwordc(reset) is empty"));
+        return;
+    }
+
+    let address = format!("{}:{}", config.rcon.ip, config.rcon.port);
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("{}", coloriser!("Failed to serve RCON at c(on_blue) {} c(reset): {}", address, err.to_string()));
+            return;
+        }
+    };
+
+    info!("{}", coloriser!("Serving RCON at c(on_blue) {} ", address));
+
+    loop {
+        let Ok((stream, address)) = listener.accept().await else {
+            continue;
+        };
+
+        RUNTIME.spawn(async move {
+            if let Err(err) = handle(stream, address).await {
+                error!("{}", coloriser!("[rcon/c(dark_blue){}c(reset)] {}", address, err.to_string()));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_fit_the_limit() {
+        let text = "a".repeat(10);
+
+        assert_eq!(chunks(&text, 4), vec!["aaaa", "aaaa", "aa"]);
+        assert!(chunks("", 4).is_empty());
+    }
+
+    #[test]
+    fn chunks_keep_characters_whole() {
+        // Each "é" is two bytes, so a 3 byte limit can only fit one
+        let text = "éééé";
+
+        assert_eq!(chunks(text, 3), vec!["é", "é", "é", "é"]);
+        assert_eq!(chunks(text, 4).concat(), text);
+    }
+
+    #[test]
+    fn locks_out_after_repeated_failures() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        for _ in 0..LOCKOUT_AFTER {
+            assert!(!locked_out(ip));
+            record_auth(ip, false);
+        }
+        assert!(locked_out(ip));
+
+        record_auth(ip, true);
+        assert!(!locked_out(ip));
+    }
+}
@@ -0,0 +1,162 @@
+//! Rhai scripts evaluated on every login after the built-in filters.
+//!
+//! Every `.rhai` file in the script directory is run, in the order of their
+//! file names, with the constants `ip`, `handshake`, `login`, `connections`,
+//! `total_connections` and `attack_mode` in scope. A script returning `true`
+//! (or nothing) allows the login, `false` kicks with `player_script_kick` and
+//! a string kicks with that string as the reason. Anything else is an error,
+//! handled as `scripts.on_error` says.
+//!
+//! The file watcher picks up new, changed and deleted scripts.
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use arc_swap::ArcSwap;
+use log::{error, info};
+use once_cell::sync::Lazy;
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+
+use crate::file::config_file::ScriptErrorVerdict;
+use crate::file::VIGILANT_CONFIG;
+use crate::macros::coloriser;
+use crate::session::Session;
+
+static ENGINE: Lazy<Engine> = Lazy::new(|| {
+    let mut engine = Engine::new();
+    engine.set_max_operations(VIGILANT_CONFIG.load().scripts.max_operations);
+    engine
+});
+
+/// The compiled scripts in the order they run, swapped as a whole by
+/// [`refresh`] so logins never wait on it
+static SCRIPTS: Lazy<ArcSwap<Vec<Script>>> = Lazy::new(|| ArcSwap::from_pointee(Vec::new()));
+
+struct Script {
+    path: PathBuf,
+    modified: SystemTime,
+    ast: Arc<AST>,
+}
+
+pub struct ScriptContext {
+    pub ip: String,
+    pub session: Session,
+    pub username: String,
+    pub profile_id: Option<String>,
+    pub connections: usize,
+    pub total_connections: usize,
+    pub attack_mode: bool,
+}
+
+pub enum ScriptVerdict {
+    Allow,
+    Deny(Option<String>),
+}
+
+/// Picks up new, changed and deleted scripts from the script directory, only
+/// recompiling the ones that changed
+pub fn refresh() {
+    let config = VIGILANT_CONFIG.load();
+    let directory = &config.scripts.directory;
+
+    if !config.scripts.active {
+        return;
+    }
+
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => {
+            if let Err(err) = fs::create_dir_all(directory) {
+                error!("{}", coloriser!("Failed to create the script directory c(dark_purple){}c(reset): {}", directory, err.to_string()));
+            }
+            SCRIPTS.store(Arc::new(Vec::new()));
+            return;
+        }
+    };
+
+    let mut paths = entries.flatten().map(|entry| entry.path()).filter(|path| path.extension().is_some_and(|v| v == "rhai")).collect::<Vec<_>>();
+    paths.sort();
+
+    let loaded = SCRIPTS.load();
+    let mut scripts = Vec::new();
+    let mut changed = paths.len() != loaded.len();
+
+    for path in paths {
+        let modified = fs::metadata(&path).and_then(|v| v.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+
+        if let Some(script) = loaded.iter().find(|v| v.path == path && v.modified == modified) {
+            scripts.push(Script { path, modified, ast: script.ast.clone() });
+            continue;
+        }
+
+        changed = true;
+
+        match ENGINE.compile_file(path.clone()) {
+            Ok(ast) => {
+                info!("{}", coloriser!("Loaded script c(dark_purple){}", path.display()));
+                scripts.push(Script { path, modified, ast: Arc::new(ast) });
+            }
+            Err(err) => error!("{}", coloriser!("Failed to compile script c(dark_purple){}c(reset): {}", path.display(), err.to_string())),
+        }
+    }
+
+    if changed {
+        SCRIPTS.store(Arc::new(scripts));
+    }
+}
+
+fn scope(context: &ScriptContext) -> Scope<'static> {
+    let mut handshake = Map::new();
+    if let Some(info) = &context.session.handshake {
+        handshake.insert("protocol_version".into(), (info.protocol_version as i64).into());
+        handshake.insert("server_address".into(), info.server_address.clone().into());
+        handshake.insert("server_port".into(), (info.server_port as i64).into());
+        handshake.insert("next_state".into(), info.next_state.clone().into());
+    }
+
+    let mut login = Map::new();
+    login.insert("username".into(), context.username.clone().into());
+    login.insert("profile_id".into(), context.profile_id.clone().map_or(Dynamic::UNIT, |v| v.into()));
+
+    let mut scope = Scope::new();
+    scope.push_constant("ip", context.ip.clone());
+    scope.push_constant("handshake", handshake);
+    scope.push_constant("login", login);
+    scope.push_constant("connections", context.connections as i64);
+    scope.push_constant("total_connections", context.total_connections as i64);
+    scope.push_constant("attack_mode", context.attack_mode);
+    scope
+}
+
+/// Runs every script in turn, stopping at the first one that denies the login
+pub fn evaluate(context: ScriptContext) -> ScriptVerdict {
+    let scripts = SCRIPTS.load();
+
+    for script in scripts.iter() {
+        let mut scope = scope(&context);
+
+        let result = ENGINE.eval_ast_with_scope::<Dynamic>(&mut scope, &script.ast).map_err(|err| err.to_string()).and_then(|result| match result {
+            result if result.is_unit() => Ok(None),
+            result if result.is_bool() => Ok((!result.as_bool().unwrap_or(false)).then_some(None)),
+            result if result.is_string() => Ok(Some(result.into_string().ok())),
+            result => Err(format!("Returned a {} instead of a bool or a string", result.type_name())),
+        });
+
+        match result {
+            Ok(None) => continue,
+            Ok(Some(reason)) => return ScriptVerdict::Deny(reason),
+            Err(err) => {
+                error!("{}", coloriser!("[c(dark_purple){}c(reset)] {}", script.path.display(), err));
+
+                match VIGILANT_CONFIG.load().scripts.on_error {
+                    ScriptErrorVerdict::Allow => continue,
+                    ScriptErrorVerdict::Deny => return ScriptVerdict::Deny(None),
+                }
+            }
+        }
+    }
+
+    ScriptVerdict::Allow
+}
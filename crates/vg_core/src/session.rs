@@ -0,0 +1,56 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::file::config_file::ListenerConfig;
+use crate::fingerprint::Fingerprint;
+use crate::packet::c2s;
+use crate::traffic::Traffic;
+use crate::SESSIONS;
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Clone, Serialize)]
+pub struct Session {
+    pub id: u64,
+    pub address: SocketAddr,
+    pub listener: Arc<ListenerConfig>,
+    /// Guardian profile picked at the handshake, empty for `[guardian]`
+    pub profile: String,
+    pub connected_at: i64,
+    pub handshake: Option<HandshakeInfo>,
+    pub username: Option<String>,
+    pub traffic: Arc<Traffic>,
+    pub fingerprint: Arc<Fingerprint>,
+    /// Sending a reason here disconnects the session with it
+    #[serde(skip)]
+    pub kick: UnboundedSender<String>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct HandshakeInfo {
+    pub protocol_version: i32,
+    pub server_address: String,
+    pub server_port: u16,
+    pub next_state: String,
+}
+
+impl Session {
+    pub fn new(address: SocketAddr, listener: Arc<ListenerConfig>, kick: UnboundedSender<String>) -> Self {
+        Self { id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed), address, listener, profile: String::new(), connected_at: chrono::Utc::now().timestamp(), handshake: None, username: None, traffic: Arc::default(), fingerprint: Arc::default(), kick }
+    }
+}
+
+impl From<&c2s::Handshake> for HandshakeInfo {
+    fn from(packet: &c2s::Handshake) -> Self {
+        Self { protocol_version: packet.protocol_version.0, server_address: packet.server_address.clone(), server_port: packet.server_port, next_state: format!("{:?}", packet.next_state).to_lowercase() }
+    }
+}
+
+/// Kicks every session `filter` matches, returning how many it did
+pub async fn kick<F: Fn(&Session) -> bool>(filter: F, reason: &str) -> usize {
+    SESSIONS.lock().await.values().filter(|v| filter(v)).filter(|v| v.kick.send(reason.to_string()).is_ok()).count()
+}
@@ -0,0 +1,91 @@
+//! Graceful shutdown and drain mode.
+//!
+//! Draining refuses new logins, showing the restarting MOTD, while the players
+//! already in keep playing. Stopping drains, closes the listener, kicks every
+//! session with `server_shutdown_kick` and waits up to `shutdown.timeout` for
+//! them to close before flushing the IP filter databases and the logs.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::file::{IP_BANLIST_DB, IP_BLACKLIST_DB, IP_WHITELIST_DB, VERIFIED_DB, VIGILANT_CONFIG, VIGILANT_LANG};
+use crate::macros::coloriser;
+use crate::{session, RUNTIME, SESSIONS};
+
+pub static DRAINING: AtomicBool = AtomicBool::new(false);
+pub static STOP_ACCEPTING: Lazy<Notify> = Lazy::new(Notify::new);
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Reporting the change is up to the caller, the console prints what the
+/// command returns
+pub fn drain(active: bool) {
+    DRAINING.store(active, Ordering::Relaxed);
+}
+
+/// Starts the graceful shutdown, calling it again while it is still running
+/// exits right away
+pub fn stop() {
+    if STOPPING.swap(true, Ordering::SeqCst) {
+        warn!("{}", coloriser!("c(bright_red)Forcing the shutdown"));
+        log::logger().flush();
+        std::process::exit(1);
+    }
+
+    RUNTIME.spawn(graceful());
+}
+
+async fn graceful() {
+    info!("{}", coloriser!("c(bright_red)Stopping"));
+
+    DRAINING.store(true, Ordering::Relaxed);
+    STOP_ACCEPTING.notify_waiters();
+
+    session::kick(|_| true, &VIGILANT_LANG.load().server_shutdown_kick).await;
+
+    let deadline = Instant::now() + Duration::from_secs(VIGILANT_CONFIG.load().shutdown.timeout);
+    while !SESSIONS.lock().await.is_empty() && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let remaining = SESSIONS.lock().await.len();
+    if remaining > 0 {
+        warn!("{}", coloriser!("{} session(s) did not close in time", remaining));
+    }
+
+    let databases = [("IP blacklist", IP_BLACKLIST_DB.lock().unwrap().flush()), ("IP whitelist", IP_WHITELIST_DB.lock().unwrap().flush()), ("ban list", IP_BANLIST_DB.lock().unwrap().flush()), ("verified IPs", VERIFIED_DB.lock().unwrap().flush())];
+    for (name, result) in databases {
+        if let Err(err) = result {
+            warn!("{}", coloriser!("Failed to save the {}: {}", name, err.to_string()));
+        }
+    }
+
+    info!("{}", coloriser!("c(bright_red)Stopped"));
+    log::logger().flush();
+
+    std::process::exit(0);
+}
+
+/// Treats SIGTERM and Ctrl-C like the `stop` command
+pub async fn signals() {
+    #[cfg(unix)]
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+
+    loop {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+
+        #[cfg(not(unix))]
+        tokio::signal::ctrl_c().await.expect("Failed to listen for Ctrl-C");
+
+        stop();
+    }
+}
@@ -0,0 +1,85 @@
+//! Health check of the server and cache of its status response. The server
+//! is pinged every `proxy.status_cache.ttl` seconds, which keeps
+//! `SERVER_ALIVE` current for the limbo, and while `proxy.status_cache.active`
+//! the gate answers status requests and pings from the last response on its
+//! own, so a flood of server list pings never reaches the server.
+
+use std::time::Duration;
+
+use arc_swap::ArcSwapOption;
+use log::warn;
+use once_cell::sync::Lazy;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use valence_protocol::decoder::{decode_packet, PacketDecoder};
+use valence_protocol::packet::c2s::handshake::handshake::NextState;
+use valence_protocol::var_int::VarInt;
+
+use crate::file::VIGILANT_CONFIG;
+use crate::macros::coloriser;
+use crate::packet::{c2s, s2c};
+use crate::{backend_alive, make_bytes};
+
+static CACHE: Lazy<ArcSwapOption<String>> = Lazy::new(|| ArcSwapOption::from(None));
+
+/// The last status response of the server, `None` when the cache is off or
+/// the server didn't answer the last ping
+pub fn cached() -> Option<String> {
+    CACHE.load().as_deref().cloned()
+}
+
+async fn ping() -> anyhow::Result<String> {
+    let config = VIGILANT_CONFIG.load();
+    let mut stream = TcpStream::connect(format!("{}:{}", config.server.ip, config.server.port)).await?;
+
+    // -1 asks for the status without claiming any version
+    let handshake = make_bytes!(c2s::Handshake { protocol_version: VarInt(-1), server_address: config.server.ip.clone(), server_port: config.server.port, next_state: NextState::Status });
+    stream.write_all(&handshake).await?;
+    stream.write_all(&make_bytes!(c2s::QueryRequest)).await?;
+
+    let mut decoder = PacketDecoder::new();
+
+    loop {
+        if let Some(frame) = decoder.try_next_packet()? {
+            let packet: s2c::QueryResponse = decode_packet(&frame)?;
+            return Ok(packet.json);
+        }
+
+        decoder.reserve(4096);
+        let mut buf = decoder.take_capacity();
+
+        anyhow::ensure!(stream.read_buf(&mut buf).await? > 0, "Connection closed");
+
+        decoder.queue_bytes(buf);
+    }
+}
+
+/// Keeps whether the server is up current, and the cache fresh
+pub async fn refresher() {
+    loop {
+        let config = VIGILANT_CONFIG.load();
+        let cache = &config.proxy.status_cache;
+        let ttl = Duration::from_secs(cache.ttl.max(1));
+
+        match tokio::time::timeout(ttl, ping()).await {
+            Ok(Ok(json)) => {
+                CACHE.store(cache.active.then(|| json.into()));
+                backend_alive(true);
+            }
+            Ok(Err(err)) => {
+                CACHE.store(None);
+                if backend_alive(false) {
+                    warn!("{}", coloriser!("Failed to ping the server: {}", err.to_string()));
+                }
+            }
+            Err(_) => {
+                CACHE.store(None);
+                if backend_alive(false) {
+                    warn!("{}", coloriser!("Timed out pinging the server"));
+                }
+            }
+        }
+
+        tokio::time::sleep(ttl).await;
+    }
+}
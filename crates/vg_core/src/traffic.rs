@@ -0,0 +1,154 @@
+//! Byte and packet counts per session and per IP, and the throttling of what
+//! clients send with `guardian.bandwidth`.
+//!
+//! Packets are only counted while the proxy can still tell the frames apart,
+//! an encrypted session keeps counting bytes but not packets.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::metrics;
+use crate::packet::PacketDirection;
+
+static IPS: Lazy<Mutex<HashMap<String, Arc<Traffic>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Default, Serialize)]
+pub struct Traffic {
+    pub bytes_c2s: AtomicU64,
+    pub bytes_s2c: AtomicU64,
+    pub packets_c2s: AtomicU64,
+    pub packets_s2c: AtomicU64,
+    #[serde(skip)]
+    bucket: Mutex<Bucket>,
+}
+
+/// Token bucket holding up to a second worth of bytes, it goes into debt
+/// instead of splitting a read so the caller just waits it off
+#[derive(Default)]
+struct Bucket {
+    tokens: f64,
+    last: Option<Instant>,
+}
+
+impl Traffic {
+    fn add(&self, direction: &PacketDirection, bytes: u64, packets: u64) {
+        let (bytes_total, packets_total) = match direction {
+            PacketDirection::C2S => (&self.bytes_c2s, &self.packets_c2s),
+            PacketDirection::S2C => (&self.bytes_s2c, &self.packets_s2c),
+        };
+
+        bytes_total.fetch_add(bytes, Ordering::Relaxed);
+        packets_total.fetch_add(packets, Ordering::Relaxed);
+    }
+
+    /// Takes `bytes` out of the bucket refilled at `rate` bytes per second,
+    /// returning how long to wait before reading again
+    pub fn throttle(&self, bytes: usize, rate: u64) -> Duration {
+        if rate == 0 {
+            return Duration::ZERO;
+        }
+
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = bucket.last.map_or(1.0, |v| now.duration_since(v).as_secs_f64());
+
+        bucket.last = Some(now);
+        bucket.tokens = (bucket.tokens + elapsed * rate as f64).min(rate as f64) - bytes as f64;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate as f64)
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+        format!("⬇ {:.2}MB ⬆ {:.2}MB, {}/{} packets", load(&self.bytes_c2s) as f64 / 1e+6, load(&self.bytes_s2c) as f64 / 1e+6, load(&self.packets_c2s), load(&self.packets_s2c))
+    }
+}
+
+/// The traffic shared by every session of `ip`
+pub fn ip(ip: &str) -> Arc<Traffic> {
+    IPS.lock().unwrap().entry(ip.to_string()).or_default().clone()
+}
+
+/// Forgets `ip` once its last session closed
+pub fn release(ip: &str) {
+    IPS.lock().unwrap().remove(ip);
+}
+
+pub fn ips() -> Vec<(String, Arc<Traffic>)> {
+    IPS.lock().unwrap().iter().map(|(ip, traffic)| (ip.clone(), traffic.clone())).collect()
+}
+
+/// Counts forwarded bytes for the session, its IP and the whole proxy
+pub fn record(direction: &PacketDirection, session: &Traffic, ip: &Traffic, bytes: usize, packets: usize) {
+    session.add(direction, bytes as u64, packets as u64);
+    ip.add(direction, bytes as u64, packets as u64);
+
+    match direction {
+        PacketDirection::C2S => {
+            metrics::BYTES_C2S.fetch_add(bytes as u64, Ordering::Relaxed);
+            metrics::PACKETS_C2S.fetch_add(packets as u64, Ordering::Relaxed);
+        }
+        PacketDirection::S2C => {
+            metrics::BYTES_S2C.fetch_add(bytes as u64, Ordering::Relaxed);
+            metrics::PACKETS_S2C.fetch_add(packets as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn near(wait: Duration, secs: f64) -> bool {
+        (wait.as_secs_f64() - secs).abs() < 0.05
+    }
+
+    #[test]
+    fn starts_with_a_second_of_bytes() {
+        let traffic = Traffic::default();
+
+        assert_eq!(traffic.throttle(400, 1000), Duration::ZERO);
+        assert_eq!(traffic.throttle(600, 1000), Duration::ZERO);
+        assert!(near(traffic.throttle(500, 1000), 0.5));
+    }
+
+    #[test]
+    fn goes_into_debt() {
+        let traffic = Traffic::default();
+
+        assert!(near(traffic.throttle(3000, 1000), 2.0));
+        assert!(near(traffic.throttle(0, 1000), 2.0));
+    }
+
+    #[test]
+    fn refills_up_to_a_second() {
+        let traffic = Traffic::default();
+        traffic.throttle(1000, 1000);
+        traffic.bucket.lock().unwrap().last = Some(Instant::now() - Duration::from_secs(10));
+
+        assert_eq!(traffic.throttle(1000, 1000), Duration::ZERO);
+        assert!(near(traffic.throttle(500, 1000), 0.5));
+    }
+
+    #[test]
+    fn zero_rate_is_uncapped() {
+        assert_eq!(Traffic::default().throttle(usize::MAX, 0), Duration::ZERO);
+    }
+
+    #[test]
+    fn rounds_the_summary() {
+        let traffic = Traffic::default();
+        traffic.add(&PacketDirection::C2S, 1_234_567, 3);
+
+        assert_eq!(traffic.summary(), "⬇ 1.23MB ⬆ 0.00MB, 3/0 packets");
+    }
+}
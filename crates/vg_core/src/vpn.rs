@@ -0,0 +1,162 @@
+//! VPN lookups for `[guardian.vpn_filter]`, asked to proxycheck.io as told by
+//! `[guardian.vpn_lookup]`. The IP filter databases answer first, then the
+//! verdicts the provider gave within `ttl`. Lookups of the same IP are shared,
+//! at most `concurrency` of them run at once, and one whose request fails or
+//! outlasts `timeout` gets the `fallback` verdict without being remembered.
+//! `fallback` is `allow` unless set otherwise, so an outage of the provider
+//! lets everyone in rather than no one.
+//!
+//! Verdicts are only kept in memory and forgotten on a restart, they aren't
+//! written to the IP blacklist and whitelist databases anymore, which only
+//! hold the IPs put there by hand.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::future::{BoxFuture, Shared};
+use futures::FutureExt;
+use log::warn;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use tokio::sync::Semaphore;
+
+use crate::file::config_file::VpnFallback;
+use crate::file::{IP_BLACKLIST_DB, IP_WHITELIST_DB, VIGILANT_CONFIG};
+use crate::guardian::{canonical_ip, ip_key};
+use crate::macros::coloriser;
+use crate::{metrics, RUNTIME};
+
+/// Whether the provider saw a VPN, and when it was asked
+static VERDICTS: Lazy<Mutex<HashMap<String, (bool, Instant)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A lookup every check of the same IP waits on, `None` when the provider failed
+type Lookup = Shared<BoxFuture<'static, Option<bool>>>;
+
+/// Lookups still waiting on the provider
+static LOOKUPS: Lazy<Mutex<HashMap<String, Lookup>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+static PERMITS: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(VIGILANT_CONFIG.load().guardian.vpn_lookup.concurrency.max(1)));
+
+/// The verdict on an IP without asking the provider, if there is one
+fn known(key: &str) -> Option<bool> {
+    if IP_BLACKLIST_DB.lock().unwrap().has(key) {
+        return Some(true);
+    }
+    if IP_WHITELIST_DB.lock().unwrap().has(key) {
+        return Some(false);
+    }
+
+    let ttl = Duration::from_secs(VIGILANT_CONFIG.load().guardian.vpn_lookup.ttl);
+    VERDICTS.lock().unwrap().get(key).filter(|(_, asked)| asked.elapsed() < ttl).map(|(vpn, _)| *vpn)
+}
+
+/// Forgets the verdicts older than `ttl` once a minute
+pub async fn evictor() {
+    loop {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+
+        let ttl = Duration::from_secs(VIGILANT_CONFIG.load().guardian.vpn_lookup.ttl);
+        VERDICTS.lock().unwrap().retain(|_, (_, asked)| asked.elapsed() < ttl);
+    }
+}
+
+/// Asks the provider about `ip`, joining the lookup of its key if one is
+/// already running
+fn lookup(ip: IpAddr, key: String) -> Shared<BoxFuture<'static, Option<bool>>> {
+    let mut lookups = LOOKUPS.lock().unwrap();
+
+    if let Some(lookup) = lookups.get(&key) {
+        return lookup.clone();
+    }
+
+    let task = RUNTIME.spawn({
+        let key = key.clone();
+        async move {
+            let vpn = ask(ip).await;
+            if let Some(vpn) = vpn {
+                VERDICTS.lock().unwrap().insert(key.clone(), (vpn, Instant::now()));
+            }
+            LOOKUPS.lock().unwrap().remove(&key);
+            vpn
+        }
+    });
+
+    let lookup = async move { task.await.ok().flatten() }.boxed().shared();
+    lookups.insert(key, lookup.clone());
+    lookup
+}
+
+/// `None` when the provider failed or didn't answer in time
+async fn ask(ip: IpAddr) -> Option<bool> {
+    let timeout = VIGILANT_CONFIG.load().guardian.vpn_lookup.timeout;
+
+    // Only the request is timed, not the wait for a turn to send it
+    let Ok(_permit) = PERMITS.acquire().await else {
+        return None;
+    };
+
+    let answer = tokio::time::timeout(Duration::from_millis(timeout.max(1)), async {
+        let lookup = Instant::now();
+        let resp = reqwest::get(format!("https://proxycheck.io/v2/{ip}?vpn=2&asn=0&risk=1")).await?.text().await;
+        metrics::VPN_LOOKUP.observe(lookup.elapsed());
+
+        anyhow::Ok(serde_json::from_str::<Value>(&resp?)?[ip.to_string()]["risk"].as_u64())
+    })
+    .await;
+
+    match answer {
+        Ok(Ok(Some(risk))) => Some(risk >= 50),
+        Ok(Ok(None)) => {
+            warn!("{}", coloriser!("[/c(dark_blue){}c(reset)] The VPN lookup got no risk score", ip));
+            None
+        }
+        Ok(Err(e)) => {
+            warn!("{}", coloriser!("[/c(dark_blue){}c(reset)] The VPN lookup failed: {}", ip, e));
+            None
+        }
+        Err(_) => {
+            warn!("{}", coloriser!("[/c(dark_blue){}c(reset)] The VPN lookup took longer than {}ms", ip, timeout));
+            None
+        }
+    }
+}
+
+/// Whether `ip` is a VPN or a proxy
+pub async fn blacklisted(ip: IpAddr) -> bool {
+    let ip = canonical_ip(ip);
+    let key = ip_key(ip);
+
+    if ip.is_loopback() {
+        return false;
+    }
+    if let Some(vpn) = known(&key) {
+        metrics::VPN_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+        return vpn;
+    }
+
+    metrics::VPN_CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+
+    match lookup(ip, key).await {
+        Some(vpn) => vpn,
+        None => {
+            metrics::VPN_FALLBACKS.fetch_add(1, Ordering::Relaxed);
+            matches!(VIGILANT_CONFIG.load().guardian.vpn_lookup.fallback, VpnFallback::Deny)
+        }
+    }
+}
+
+/// Starts looking `ip` up ahead of its login, when `guardian.vpn_lookup.prewarm`
+/// is on
+pub fn prewarm(ip: IpAddr) {
+    let ip = canonical_ip(ip);
+    let key = ip_key(ip);
+
+    if !VIGILANT_CONFIG.load().guardian.vpn_lookup.prewarm || ip.is_loopback() || known(&key).is_some() {
+        return;
+    }
+
+    drop(lookup(ip, key));
+}
@@ -161,7 +161,7 @@ pub fn create_colorizer(input: TokenStream) -> TokenStream {
 
     let rpl_n = rpl_vec_n.join(" ");
     let rpl = rpl_vec.join("");
-    let res = format!(r###"#[macro_export]{}macro_rules! colorizer {{($fmt_str:literal) => {{{{if crate::file::VIGILANT_CONFIG.colorize {{format!($fmt_str){rpl}}} else {{ format!($fmt_str){rpl_n} }} }}}};($fmt_str:literal, $($args:expr),*) => {{{{if crate::file::VIGILANT_CONFIG.colorize {{ format!($fmt_str, $($args),*){rpl} }} else {{ format!($fmt_str, $($args),*){rpl_n} }} }}}};}}{}pub use colorizer as coloriser;"###, "\n", "\n");
+    let res = format!(r###"#[macro_export]{}macro_rules! colorizer {{($fmt_str:literal) => {{{{if crate::file::VIGILANT_CONFIG.load().colorize {{format!($fmt_str){rpl}}} else {{ format!($fmt_str){rpl_n} }} }}}};($fmt_str:literal, $($args:expr),*) => {{{{if crate::file::VIGILANT_CONFIG.load().colorize {{ format!($fmt_str, $($args),*){rpl} }} else {{ format!($fmt_str, $($args),*){rpl_n} }} }}}};}}{}pub use colorizer as coloriser;"###, "\n", "\n");
 
     res.parse().unwrap()
 }